@@ -1,12 +1,19 @@
 use crate::util::InputErrorType;
 
-use super::config;
-use super::util::{Error, ErrorType};
+use super::config::{self, DSAData, SpecialAbilityConfig};
+use super::util::{
+    read_file_recovering, remove_file_atomic_async, write_file_atomic_async, Error, ErrorType,
+    OutputWrapper,
+};
 use serde::Deserialize;
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use tokio::fs;
 
 const LOADED_CHARACTER_FILE: &'static str = "loaded_character";
+//The range of plausible values for attributes and skills, values outside are reported as warnings
+pub const PLAUSIBLE_ATTRIBUTE_LEVELS: (i64, i64) = (1, 25);
+pub const PLAUSIBLE_SKILL_LEVELS: (i64, i64) = (0, 25);
 
 mod default {
     pub fn skills() -> Vec<super::CharacterSkill> {
@@ -21,6 +28,36 @@ mod default {
     pub fn chants() -> Vec<super::CharacterChant> {
         Vec::new()
     }
+    pub fn specializations() -> Vec<String> {
+        Vec::new()
+    }
+    pub fn advantages() -> Vec<super::CharacterAdvantage> {
+        Vec::new()
+    }
+    pub fn advantage_talents() -> Vec<String> {
+        Vec::new()
+    }
+    pub fn specialabilities() -> Vec<super::CharacterSpecialAbility> {
+        Vec::new()
+    }
+    pub fn special_ability_level() -> i64 {
+        1
+    }
+    pub fn ap() -> super::CharacterAP {
+        super::CharacterAP {
+            total: ap_value(),
+            spent: ap_value(),
+        }
+    }
+    pub fn ap_value() -> i64 {
+        0
+    }
+    pub fn custom_technique_ranged() -> bool {
+        false
+    }
+    pub fn custom_technique_attributes() -> Vec<String> {
+        Vec::new()
+    }
 }
 
 #[derive(Deserialize)]
@@ -35,6 +72,23 @@ pub struct Character {
     spells: Vec<CharacterSpell>,
     #[serde(default = "default::chants")]
     chants: Vec<CharacterChant>,
+    #[serde(default = "default::advantages")]
+    advantages: Vec<CharacterAdvantage>,
+    #[serde(default = "default::advantages")]
+    disadvantages: Vec<CharacterAdvantage>,
+    #[serde(default = "default::specialabilities")]
+    specialabilities: Vec<CharacterSpecialAbility>,
+    #[serde(default = "default::ap")]
+    ap: CharacterAP,
+}
+
+// The adventure points (AP) of a character
+#[derive(Deserialize)]
+pub struct CharacterAP {
+    #[serde(default = "default::ap_value")]
+    total: i64,
+    #[serde(default = "default::ap_value")]
+    spent: i64,
 }
 
 #[derive(Deserialize)]
@@ -50,6 +104,13 @@ pub enum IdOrCustomInfo {
 }
 
 impl IdOrCustomInfo {
+    fn name(&self) -> &String {
+        match self {
+            IdOrCustomInfo::Id(id) => id,
+            IdOrCustomInfo::RuleElement { name, .. } => name,
+        }
+    }
+
     fn matches_name(&self, input_name: &str) -> bool {
         match self {
             IdOrCustomInfo::Id(id) => id.eq_ignore_ascii_case(input_name),
@@ -66,21 +127,56 @@ pub enum IdOrCustomId {
     #[serde(rename = "id")]
     Id(String),
     #[serde(rename = "ruleelement")]
-    RuleElement { name: String },
+    RuleElement {
+        name: String,
+        #[serde(default = "default::custom_technique_ranged")]
+        ranged: bool,
+        // The primary attributes of the technique, used for the parry level
+        #[serde(rename = "primary")]
+        #[serde(default = "default::custom_technique_attributes")]
+        attributes: Vec<String>,
+    },
 }
 
 impl IdOrCustomId {
     fn matches_name(&self, input_name: &str) -> bool {
         match self {
             IdOrCustomId::Id(id) => id.eq_ignore_ascii_case(input_name),
-            IdOrCustomId::RuleElement { name } => name.eq_ignore_ascii_case(input_name),
+            IdOrCustomId::RuleElement { name, .. } => name.eq_ignore_ascii_case(input_name),
         }
     }
 }
 
 #[derive(Deserialize)]
 pub struct CharacterSkill {
+    #[serde(flatten)]
+    id_or_rule_element: IdOrCustomInfo,
+    level: i64,
+    // The areas of use (Anwendungsgebiete) the character has specialized in
+    #[serde(default = "default::specializations")]
+    specializations: Vec<String>,
+}
+
+// An advantage or disadvantage, optionally restricted to some talents (e.g. Begabung or Unfaehig)
+#[derive(Deserialize)]
+pub struct CharacterAdvantage {
+    id: String,
+    #[serde(default = "default::advantage_talents")]
+    talents: Vec<String>,
+}
+
+impl CharacterAdvantage {
+    fn applies_to_talent(&self, talent_id: &str) -> bool {
+        self.talents
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(talent_id))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CharacterSpecialAbility {
     id: String,
+    #[serde(default = "default::special_ability_level")]
     level: i64,
 }
 
@@ -111,17 +207,110 @@ pub struct CharacterChant {
     level: Option<i64>,
 }
 
+/*
+The problems found when validating a character against the dsa data.
+Errors make the character unusable, warnings are only reported
+*/
+pub struct ValidationReport {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl ValidationReport {
+    pub fn is_fatal(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    pub fn output(&self, output: &mut impl OutputWrapper) {
+        if self.errors.is_empty() && self.warnings.is_empty() {
+            return;
+        }
+        output.output_line(&format!(
+            "Found {} error(s) and {} warning(s) in the character:",
+            self.errors.len(),
+            self.warnings.len()
+        ));
+        for error in &self.errors {
+            output.output_line(&format!("Error: {}", error));
+        }
+        for warning in &self.warnings {
+            output.output_line(&format!("Warning: {}", warning));
+        }
+    }
+
+    fn check_level(&mut self, kind: &str, id: &str, level: i64, plausible: (i64, i64)) {
+        if level < plausible.0 || level > plausible.1 {
+            self.warnings.push(format!(
+                "Implausible level {} for {} \"{}\" (expected {} to {})",
+                level, kind, id, plausible.0, plausible.1
+            ));
+        }
+    }
+
+    // Checks a talent, spell or chant, known_ids are the corresponding entries of the dsa data
+    fn check_entry<V>(
+        &mut self,
+        kind: &str,
+        entry: &IdOrCustomInfo,
+        level: Option<i64>,
+        known_ids: &HashMap<String, V>,
+        dsa_data: &DSAData,
+    ) {
+        let name = match entry {
+            IdOrCustomInfo::Id(id) => {
+                if !known_ids.contains_key(&id.to_lowercase()) {
+                    self.warnings.push(format!("Unknown {} \"{}\"", kind, id));
+                }
+                id
+            }
+            IdOrCustomInfo::RuleElement { name, attributes } => {
+                self.check_attributes(kind, name, attributes, dsa_data);
+                name
+            }
+        };
+        if let Some(level) = level {
+            self.check_level(kind, name, level, PLAUSIBLE_SKILL_LEVELS);
+        }
+    }
+
+    fn check_attributes(
+        &mut self,
+        kind: &str,
+        name: &str,
+        attributes: &[String],
+        dsa_data: &DSAData,
+    ) {
+        for attr in attributes {
+            if !dsa_data.attributes.contains_key(&attr.to_lowercase()) {
+                self.errors.push(format!(
+                    "Unknown attribute \"{}\" in the custom {} \"{}\"",
+                    attr, kind, name
+                ));
+            }
+        }
+    }
+}
+
 impl Character {
     pub async fn loaded_character() -> Result<Option<Character>, Error> {
+        match Self::loaded_character_path()? {
+            Some(char_path) => {
+                let character = Self::from_file(&char_path).await?;
+                Ok(Some(character))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Returns the path of the currently loaded character file, if there is one
+    pub fn loaded_character_path() -> Result<Option<PathBuf>, Error> {
         let mut path = config::get_config_dir()?;
         path.push(LOADED_CHARACTER_FILE);
-        if Path::exists(&path) {
-            let char_path = std::fs::read_to_string(&path)?;
-            let char_path = Path::new(&char_path);
-            let character = Self::from_file(&char_path).await?;
-            Ok(Some(character))
-        } else {
-            Ok(None)
+        match read_file_recovering(&path, |data| std::str::from_utf8(data).is_ok())? {
+            Some(char_path) => Ok(Some(PathBuf::from(
+                String::from_utf8_lossy(&char_path).as_ref(),
+            ))),
+            None => Ok(None),
         }
     }
 
@@ -145,12 +334,11 @@ impl Character {
         Ok(character)
     }
 
-    pub async fn load(path: &str) -> Result<Character, Error> {
-        let character_path = Path::new(path);
-        let character_path = fs::canonicalize(character_path).await?;
+    pub async fn load(path: impl AsRef<Path>) -> Result<Character, Error> {
+        let character_path = fs::canonicalize(path).await?;
         let mut path = config::get_config_dir()?;
         path.push(LOADED_CHARACTER_FILE);
-        fs::write(&path, character_path.to_str().unwrap()).await?;
+        write_file_atomic_async(path, character_path.to_str().unwrap().as_bytes().to_vec()).await?;
         match Character::loaded_character().await {
             Ok(Some(c)) => Ok(c),
             Ok(None) => Err(Error::new(
@@ -164,23 +352,245 @@ impl Character {
     pub async fn unload() -> Result<(), Error> {
         let mut path = config::get_config_dir()?;
         path.push(LOADED_CHARACTER_FILE);
-        fs::remove_file(&path).await?;
-        Ok(())
+        remove_file_atomic_async(path).await
     }
 
     pub fn get_name(&self) -> &str {
         &self.name
     }
 
+    // Returns the total and the unspent AP of the character
+    pub fn get_ap(&self) -> (i64, i64) {
+        (self.ap.total, self.ap.total - self.ap.spent)
+    }
+
+    /*
+    Checks all ids and values of this character against the dsa data.
+    Unknown ids would otherwise silently be treated as level 0
+    */
+    pub fn validate(&self, dsa_data: &DSAData) -> ValidationReport {
+        let mut report = ValidationReport {
+            errors: Vec::new(),
+            warnings: Vec::new(),
+        };
+
+        for attr in &self.attributes {
+            if dsa_data.attributes.contains_key(&attr.id.to_lowercase()) {
+                report.check_level(
+                    "attribute",
+                    &attr.id,
+                    attr.level,
+                    PLAUSIBLE_ATTRIBUTE_LEVELS,
+                );
+            } else {
+                report
+                    .errors
+                    .push(format!("Unknown attribute \"{}\"", attr.id));
+            }
+        }
+        let mut missing_attrs: Vec<&String> = dsa_data
+            .attributes
+            .keys()
+            .filter(|id| {
+                !self
+                    .attributes
+                    .iter()
+                    .any(|a| a.id.eq_ignore_ascii_case(id))
+            })
+            .collect();
+        missing_attrs.sort();
+        for id in missing_attrs {
+            report.errors.push(format!("Missing attribute \"{}\"", id));
+        }
+
+        for skill in &self.skills {
+            report.check_entry(
+                "talent",
+                &skill.id_or_rule_element,
+                Some(skill.level),
+                &dsa_data.talents,
+                dsa_data,
+            );
+        }
+
+        for technique in &self.combattechniques {
+            match &technique.id_or_custom_id {
+                IdOrCustomId::Id(id) => {
+                    if !dsa_data.combat_techniques.contains_key(&id.to_lowercase()) {
+                        report
+                            .warnings
+                            .push(format!("Unknown combat technique \"{}\"", id));
+                    }
+                    report.check_level(
+                        "combat technique",
+                        id,
+                        technique.level,
+                        PLAUSIBLE_SKILL_LEVELS,
+                    );
+                }
+                IdOrCustomId::RuleElement {
+                    name, attributes, ..
+                } => {
+                    report.check_attributes("combat technique", name, attributes, dsa_data);
+                    report.check_level(
+                        "combat technique",
+                        name,
+                        technique.level,
+                        PLAUSIBLE_SKILL_LEVELS,
+                    );
+                }
+            }
+        }
+
+        for spell in &self.spells {
+            report.check_entry(
+                "spell",
+                &spell.id_or_rule_element,
+                spell.level,
+                &dsa_data.spells,
+                dsa_data,
+            );
+        }
+        for chant in &self.chants {
+            report.check_entry(
+                "chant",
+                &chant.id_or_rule_element,
+                chant.level,
+                &dsa_data.chants,
+                dsa_data,
+            );
+        }
+
+        for skill in &self.skills {
+            let talent = skill.id_or_rule_element.name();
+            let mut names: Vec<String> = Vec::new();
+            for specialization in &skill.specializations {
+                let name = specialization.trim().to_lowercase();
+                if name.is_empty() {
+                    report
+                        .warnings
+                        .push(format!("Empty specialization of talent \"{}\"", talent));
+                } else if names.contains(&name) {
+                    report.warnings.push(format!(
+                        "Duplicate specialization \"{}\" of talent \"{}\"",
+                        specialization, talent
+                    ));
+                } else {
+                    names.push(name);
+                }
+            }
+        }
+
+        for (kind, advantages, known_ids) in &[
+            ("advantage", &self.advantages, &dsa_data.advantages),
+            ("disadvantage", &self.disadvantages, &dsa_data.disadvantages),
+        ] {
+            for advantage in advantages.iter() {
+                if !known_ids.contains_key(&advantage.id.to_lowercase()) {
+                    report
+                        .warnings
+                        .push(format!("Unknown {} \"{}\"", kind, advantage.id));
+                }
+                // The talents a (dis)advantage applies to are either built-in or custom talents of the character
+                for talent in &advantage.talents {
+                    if !dsa_data.talents.contains_key(&talent.to_lowercase())
+                        && !self
+                            .get_custom_talents()
+                            .any(|(name, _)| name.eq_ignore_ascii_case(talent))
+                    {
+                        report.warnings.push(format!(
+                            "Unknown talent \"{}\" in the {} \"{}\"",
+                            talent, kind, advantage.id
+                        ));
+                    }
+                }
+            }
+        }
+
+        for ability in &self.specialabilities {
+            let id = ability.id.to_lowercase();
+            // Special abilities are either known for their bonuses or because they unlock a combat maneuver
+            let maneuver_max_level = dsa_data
+                .combat_maneuvers
+                .values()
+                .filter(|m| m.special_ability == id)
+                .map(|m| m.max_level)
+                .max();
+            match maneuver_max_level {
+                Some(max_level) => {
+                    report.check_level(
+                        "special ability",
+                        &ability.id,
+                        ability.level,
+                        (1, max_level),
+                    );
+                }
+                None if !dsa_data.special_abilities.contains_key(&id) => {
+                    report
+                        .warnings
+                        .push(format!("Unknown special ability \"{}\"", ability.id));
+                }
+                None => {}
+            }
+        }
+        report
+    }
+
+    pub fn get_skills(&self) -> impl Iterator<Item = (&String, i64)> {
+        self.skills
+            .iter()
+            .map(|s| (s.id_or_rule_element.name(), s.level))
+    }
+
     pub fn get_skill_level(&self, skill_id: &str) -> i64 {
         for skill in &self.skills {
-            if skill.id.eq_ignore_ascii_case(skill_id) {
+            if skill.id_or_rule_element.matches_name(skill_id) {
                 return skill.level;
             }
         }
         0
     }
 
+    pub fn get_skill_specializations(&self, skill_id: &str) -> &[String] {
+        for skill in &self.skills {
+            if skill.id_or_rule_element.matches_name(skill_id) {
+                return &skill.specializations;
+            }
+        }
+        &[]
+    }
+
+    /*
+    Returns the ids of all advantages that apply to the given talent
+    */
+    pub fn get_talent_advantages<'a>(
+        &'a self,
+        talent_id: &'a str,
+    ) -> impl Iterator<Item = &'a str> {
+        self.advantages
+            .iter()
+            .filter(move |a| a.applies_to_talent(talent_id))
+            .map(|a| a.id.as_str())
+    }
+
+    /*
+    Returns the ids of all disadvantages that apply to the given talent
+    */
+    pub fn get_talent_disadvantages<'a>(
+        &'a self,
+        talent_id: &'a str,
+    ) -> impl Iterator<Item = &'a str> {
+        self.disadvantages
+            .iter()
+            .filter(move |a| a.applies_to_talent(talent_id))
+            .map(|a| a.id.as_str())
+    }
+
+    // Returns the ids and levels of all attributes in the order of the character file
+    pub fn get_attributes(&self) -> impl Iterator<Item = (&String, i64)> {
+        self.attributes.iter().map(|a| (&a.id, a.level))
+    }
+
     pub fn get_attribute_level(&self, attr_id: &str) -> i64 {
         for attr in &self.attributes {
             if attr.id.eq_ignore_ascii_case(attr_id) {
@@ -190,7 +600,7 @@ impl Character {
         0
     }
 
-    fn get_technique_level(&self, technique_id: &str) -> i64 {
+    pub fn get_technique_level(&self, technique_id: &str) -> i64 {
         for technique in &self.combattechniques {
             if technique.id_or_custom_id.matches_name(technique_id) {
                 return technique.level;
@@ -226,16 +636,45 @@ impl Character {
         0
     }
 
-    pub fn get_dodge_level(&self) -> i64 {
+    // Returns the level of the given special ability or None, if the character doesn't have it
+    pub fn get_special_ability_level(&self, ability_id: &str) -> Option<i64> {
+        self.specialabilities
+            .iter()
+            .find(|a| a.id.eq_ignore_ascii_case(ability_id))
+            .map(|a| a.level)
+    }
+
+    /*
+    Sums up the bonuses of all special abilities of this character that are defined in the dsa data.
+    The bonus function returns the bonus per level of a special ability
+    */
+    fn get_special_ability_bonus(
+        &self,
+        dsa_data: &DSAData,
+        bonus: impl Fn(&SpecialAbilityConfig) -> i64,
+    ) -> i64 {
+        self.specialabilities
+            .iter()
+            .filter_map(|a| {
+                dsa_data
+                    .special_abilities
+                    .get(&a.id.to_lowercase())
+                    .map(|config| a.level * bonus(config))
+            })
+            .sum()
+    }
+
+    pub fn get_dodge_level(&self, dsa_data: &DSAData) -> i64 {
+        let ability_bonus = self.get_special_ability_bonus(dsa_data, |a| a.dodge);
         for attr in &self.attributes {
             if attr.id.eq_ignore_ascii_case("gewandtheit") {
-                return attr.level / 2;
+                return attr.level / 2 + ability_bonus;
             }
         }
-        0
+        ability_bonus
     }
 
-    pub fn get_initiative_level(&self) -> i64 {
+    pub fn get_initiative_level(&self, dsa_data: &DSAData) -> i64 {
         let mut attr_mut = 0;
         let mut attr_gew = 0;
         for attr in &self.attributes {
@@ -245,10 +684,15 @@ impl Character {
                 attr_gew = attr.level;
             }
         }
-        (attr_mut + attr_gew) / 2
+        (attr_mut + attr_gew) / 2 + self.get_special_ability_bonus(dsa_data, |a| a.initiative)
     }
 
-    pub fn get_parry_level(&self, technique_id: &str, technique_attributes: &[String]) -> i64 {
+    pub fn get_parry_level(
+        &self,
+        technique_id: &str,
+        technique_attributes: &[String],
+        dsa_data: &DSAData,
+    ) -> i64 {
         let technique_level = self.get_technique_level(technique_id);
 
         let mut max_attr = 0;
@@ -259,19 +703,58 @@ impl Character {
                 }
             }
         }
+        let ability_bonus = self.get_special_ability_bonus(dsa_data, |a| {
+            if a.parry_techniques.is_empty()
+                || a.parry_techniques
+                    .iter()
+                    .any(|t| t.eq_ignore_ascii_case(technique_id))
+            {
+                a.parry
+            } else {
+                0
+            }
+        });
         // We add 1 to the technique_level since it has to be rounded up
-        (technique_level + 1) / 2 + std::cmp::max(0, (max_attr - 8) / 3)
+        (technique_level + 1) / 2 + std::cmp::max(0, (max_attr - 8) / 3) + ability_bonus
     }
 
-    pub fn get_custom_techniques(&self) -> impl Iterator<Item = &String> {
+    // Returns the name, whether it is ranged and the primary attributes of all custom techniques
+    pub fn get_custom_techniques(&self) -> impl Iterator<Item = (&String, bool, &Vec<String>)> {
         self.combattechniques
             .iter()
             .filter_map(|t| match &t.id_or_custom_id {
                 IdOrCustomId::Id(_) => None,
-                IdOrCustomId::RuleElement { name } => Some(name),
+                IdOrCustomId::RuleElement {
+                    name,
+                    ranged,
+                    attributes,
+                } => Some((name, *ranged, attributes)),
             })
     }
 
+    pub fn get_custom_talents(&self) -> impl Iterator<Item = (&String, &Vec<String>)> {
+        self.skills
+            .iter()
+            .filter_map(|s| match &s.id_or_rule_element {
+                IdOrCustomInfo::Id(_) => None,
+                IdOrCustomInfo::RuleElement { name, attributes } => Some((name, attributes)),
+            })
+    }
+
+    // Returns the names (ids or custom names) and levels of all spells of the character
+    pub fn get_spells(&self) -> impl Iterator<Item = (&String, Option<i64>)> {
+        self.spells
+            .iter()
+            .map(|s| (s.id_or_rule_element.name(), s.level))
+    }
+
+    // Returns the names (ids or custom names) and levels of all chants of the character
+    pub fn get_chants(&self) -> impl Iterator<Item = (&String, Option<i64>)> {
+        self.chants
+            .iter()
+            .map(|c| (c.id_or_rule_element.name(), c.level))
+    }
+
     pub fn get_custom_spells(&self) -> impl Iterator<Item = (&String, &Vec<String>)> {
         self.spells
             .iter()
@@ -290,3 +773,66 @@ impl Character {
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AbstractConfig;
+
+    #[test]
+    fn unload_then_load() {
+        let dir = std::env::temp_dir().join(format!("dsa-cli-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::env::set_var("DSA_CLI_CONFIG_DIR", &dir);
+        let char_path = dir.join("minimal_character.tdc");
+        std::fs::write(&char_path, include_str!("../doc/minimal_character.tdc")).unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            Character::load(&char_path).await.unwrap();
+            Character::unload().await.unwrap();
+            // The backup must not bring the unloaded character back
+            assert!(Character::loaded_character_path().unwrap().is_none());
+            assert!(Character::loaded_character().await.unwrap().is_none());
+
+            let character = Character::load(&char_path).await.unwrap();
+            assert_eq!(character.get_name(), "Insert name");
+            assert!(Character::loaded_character().await.unwrap().is_some());
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn validates_advantages_and_special_abilities() {
+        let dsa_data: DSAData = serde_json::from_str(DSAData::DEFAULT_CONFIG).unwrap();
+        let character = Character::from_raw(
+            br#"{
+                "name": "Alrik",
+                "attributes": [
+                    {"id": "mut", "level": 12}, {"id": "klugheit", "level": 12},
+                    {"id": "intuition", "level": 12}, {"id": "charisma", "level": 12},
+                    {"id": "fingerfertigkeit", "level": 12}, {"id": "gewandtheit", "level": 12},
+                    {"id": "konstitution", "level": 12}, {"id": "koerperkraft", "level": 12}
+                ],
+                "skills": [{"id": "klettern", "level": 4, "specializations": ["Fels", "fels"]}],
+                "advantages": [{"id": "begabung", "talents": ["klettern", "fliegenn"]}],
+                "disadvantages": [{"id": "pechmagnet"}],
+                "specialabilities": [{"id": "kampfreflexe"}, {"id": "finte", "level": 4}, {"id": "wuchtschlagg"}]
+            }"#
+            .to_vec(),
+        )
+        .unwrap();
+        let report = character.validate(&dsa_data);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(
+            report.warnings,
+            vec![
+                "Duplicate specialization \"fels\" of talent \"klettern\"",
+                "Unknown talent \"fliegenn\" in the advantage \"begabung\"",
+                "Unknown disadvantage \"pechmagnet\"",
+                "Implausible level 4 for special ability \"finte\" (expected 1 to 3)",
+                "Unknown special ability \"wuchtschlagg\"",
+            ]
+        );
+    }
+}
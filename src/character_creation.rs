@@ -0,0 +1,339 @@
+use super::character::{Character, PLAUSIBLE_ATTRIBUTE_LEVELS, PLAUSIBLE_SKILL_LEVELS};
+use super::character_edit;
+use super::config::DSAData;
+use super::improvement;
+use super::util::*;
+use clap::ArgMatches;
+use serde_json::{json, Value};
+
+// The AP budget of a new character, if none is given (experience level "Erfahren")
+const DEFAULT_AP_BUDGET: i64 = 1100;
+// The levels all values of a new character start with
+const START_ATTRIBUTE_LEVEL: i64 = 8;
+const START_TALENT_LEVEL: i64 = 0;
+const START_TECHNIQUE_LEVEL: i64 = 6;
+// The input that skips the talents or combat techniques
+const SKIP_INPUT: &'static str = "-";
+// The order in which the attributes are listed on a character sheet (MU/KL/IN/CH/FF/GE/KO/KK)
+const ATTRIBUTE_ORDER: [&'static str; 8] = [
+    "mut",
+    "klugheit",
+    "intuition",
+    "charisma",
+    "fingerfertigkeit",
+    "gewandtheit",
+    "konstitution",
+    "koerperkraft",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum WizardStep {
+    Name,
+    Attributes,
+    Talents,
+    CombatTechniques,
+    Done,
+}
+
+/*
+Creates a new character step by step. The wizard doesn't do any IO itself,
+the caller shows the prompt for the current step and passes the answers to 'handle_input'
+*/
+pub struct CharacterWizard {
+    step: WizardStep,
+    ap_budget: i64,
+    // The maximum length of the name, if the character is stored by the discord bot
+    max_name_length: Option<usize>,
+    name: String,
+    attributes: Vec<(String, i64)>,
+    talents: Vec<(String, i64)>,
+    techniques: Vec<(String, i64)>,
+}
+
+impl CharacterWizard {
+    pub fn new(
+        cmd_matches: &ArgMatches,
+        max_name_length: Option<usize>,
+    ) -> Result<CharacterWizard, Error> {
+        let ap_budget = match cmd_matches.value_of("ap") {
+            None => DEFAULT_AP_BUDGET,
+            Some(ap) => match ap.parse() {
+                Ok(ap) if ap >= 0 => ap,
+                _ => {
+                    return Err(Error::new(
+                        "Unable to parse AP: Argument must be a non-negative integer",
+                        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                    ));
+                }
+            },
+        };
+        Ok(CharacterWizard {
+            step: WizardStep::Name,
+            ap_budget,
+            max_name_length,
+            name: String::new(),
+            attributes: Vec::new(),
+            talents: Vec::new(),
+            techniques: Vec::new(),
+        })
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.step == WizardStep::Done
+    }
+
+    // Returns the question for the current step
+    pub fn prompt(&self, dsa_data: &DSAData) -> String {
+        match self.step {
+            WizardStep::Name => String::from("Enter the name of the new character"),
+            WizardStep::Attributes => format!(
+                "Enter the levels of the attributes {} (in this order, separated by spaces). All attributes start at {}, you have {} AP",
+                sorted_attributes(dsa_data)
+                    .iter()
+                    .map(|a| dsa_data.get_attr_short_name(a))
+                    .collect::<Vec<&str>>()
+                    .join(", "),
+                START_ATTRIBUTE_LEVEL,
+                self.unspent_ap(dsa_data)
+            ),
+            WizardStep::Talents => format!(
+                "Enter talents and their levels, e.g. \"klettern 4, sinnesschaerfe 5\", or \"{}\" to skip. You have {} AP left",
+                SKIP_INPUT,
+                self.unspent_ap(dsa_data)
+            ),
+            WizardStep::CombatTechniques => format!(
+                "Enter combat techniques and their levels, e.g. \"dolche 10\", or \"{}\" to skip. All combat techniques start at {}, you have {} AP left",
+                SKIP_INPUT,
+                START_TECHNIQUE_LEVEL,
+                self.unspent_ap(dsa_data)
+            ),
+            WizardStep::Done => format!(
+                "Character \"{}\" is complete, {} AP are left",
+                self.name,
+                self.unspent_ap(dsa_data)
+            ),
+        }
+    }
+
+    /*
+    Processes the answer to the current step and advances to the next one.
+    If the answer is invalid, an error is returned and the step is not changed
+    */
+    pub fn handle_input(&mut self, input: &str, dsa_data: &DSAData) -> Result<(), Error> {
+        let input = input.trim();
+        match self.step {
+            WizardStep::Name => {
+                if input.is_empty() {
+                    return Err(Error::new(
+                        "The name must not be empty",
+                        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                    ));
+                }
+                if let Some(max_name_length) = self.max_name_length {
+                    if input.len() > max_name_length {
+                        return Err(Error::new(
+                            format!(
+                                "The name must not be longer than {} characters",
+                                max_name_length
+                            ),
+                            ErrorType::InvalidInput(InputErrorType::CharacterNameTooLong),
+                        ));
+                    }
+                }
+                self.name = input.to_string();
+                self.step = WizardStep::Attributes;
+            }
+            WizardStep::Attributes => {
+                let attributes = sorted_attributes(dsa_data);
+                let levels: Vec<&str> = input
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|l| !l.is_empty())
+                    .collect();
+                if levels.len() != attributes.len() {
+                    return Err(Error::new(
+                        format!(
+                            "Expected {} attribute levels, found {}",
+                            attributes.len(),
+                            levels.len()
+                        ),
+                        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                    ));
+                }
+                let mut new_attributes = Vec::new();
+                for (attribute, level) in attributes.into_iter().zip(levels) {
+                    let level = parse_level(
+                        attribute,
+                        level,
+                        (START_ATTRIBUTE_LEVEL, PLAUSIBLE_ATTRIBUTE_LEVELS.1),
+                    )?;
+                    new_attributes.push((attribute.clone(), level));
+                }
+                let old_attributes = std::mem::replace(&mut self.attributes, new_attributes);
+                if let Err(e) = self.check_budget(dsa_data) {
+                    self.attributes = old_attributes;
+                    return Err(e);
+                }
+                self.step = WizardStep::Talents;
+            }
+            WizardStep::Talents => {
+                let new_talents = parse_entries(
+                    input,
+                    "talent",
+                    (START_TALENT_LEVEL, PLAUSIBLE_SKILL_LEVELS.1),
+                    dsa_data,
+                )?;
+                let old_talents = std::mem::replace(&mut self.talents, new_talents);
+                if let Err(e) = self.check_budget(dsa_data) {
+                    self.talents = old_talents;
+                    return Err(e);
+                }
+                self.step = WizardStep::CombatTechniques;
+            }
+            WizardStep::CombatTechniques => {
+                let new_techniques = parse_entries(
+                    input,
+                    "technique",
+                    (START_TECHNIQUE_LEVEL, PLAUSIBLE_SKILL_LEVELS.1),
+                    dsa_data,
+                )?;
+                let old_techniques = std::mem::replace(&mut self.techniques, new_techniques);
+                if let Err(e) = self.check_budget(dsa_data) {
+                    self.techniques = old_techniques;
+                    return Err(e);
+                }
+                // The finished character has to pass the same validation as uploaded characters
+                let report = Character::from_raw(self.to_raw(dsa_data)?)?.validate(dsa_data);
+                if report.is_fatal() {
+                    self.techniques = old_techniques;
+                    return Err(Error::new(
+                        "The created character is invalid",
+                        ErrorType::InvalidInput(InputErrorType::InvalidFormat),
+                    ));
+                }
+                self.step = WizardStep::Done;
+            }
+            WizardStep::Done => {}
+        }
+        Ok(())
+    }
+
+    // Returns the character in the format of a .tdc file
+    pub fn to_raw(&self, dsa_data: &DSAData) -> Result<Vec<u8>, Error> {
+        let entries = |values: &Vec<(String, i64)>| {
+            values
+                .iter()
+                .map(|(id, level)| json!({ "id": id, "level": level }))
+                .collect::<Vec<Value>>()
+        };
+        let character = json!({
+            "name": self.name,
+            "ap": {
+                "total": self.ap_budget,
+                "spent": self.spent_ap(dsa_data),
+            },
+            "attributes": entries(&self.attributes),
+            "skills": entries(&self.talents),
+            "combattechniques": entries(&self.techniques),
+        });
+        Ok(serde_json::to_vec_pretty(&character)?)
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn spent_ap(&self, dsa_data: &DSAData) -> i64 {
+        let cost = |kind: &str, start_level: i64, values: &Vec<(String, i64)>| -> i64 {
+            values
+                .iter()
+                .map(|(name, level)| {
+                    improvement::improvement_cost(
+                        kind,
+                        improvement::get_improvement(kind, name, dsa_data),
+                        start_level,
+                        *level,
+                    )
+                })
+                .sum()
+        };
+        cost("attribute", START_ATTRIBUTE_LEVEL, &self.attributes)
+            + cost("talent", START_TALENT_LEVEL, &self.talents)
+            + cost("technique", START_TECHNIQUE_LEVEL, &self.techniques)
+    }
+
+    fn unspent_ap(&self, dsa_data: &DSAData) -> i64 {
+        self.ap_budget - self.spent_ap(dsa_data)
+    }
+
+    fn check_budget(&self, dsa_data: &DSAData) -> Result<(), Error> {
+        let spent = self.spent_ap(dsa_data);
+        if spent > self.ap_budget {
+            return Err(Error::new(
+                format!(
+                    "These values cost {} AP more than the budget of {} AP",
+                    spent - self.ap_budget,
+                    self.ap_budget
+                ),
+                ErrorType::InvalidInput(InputErrorType::InsufficientAP),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// The attributes are asked for in the usual order, attributes missing from it follow by id
+fn sorted_attributes(dsa_data: &DSAData) -> Vec<&String> {
+    let mut attributes: Vec<&String> = dsa_data.attributes.keys().collect();
+    attributes.sort_by_key(|id| {
+        let position = ATTRIBUTE_ORDER.iter().position(|a| a == id);
+        (position.unwrap_or(ATTRIBUTE_ORDER.len()), id.to_string())
+    });
+    attributes
+}
+
+fn parse_level(name: &str, level: &str, (min, max): (i64, i64)) -> Result<i64, Error> {
+    match level.parse() {
+        Ok(level) if (min..=max).contains(&level) => Ok(level),
+        _ => Err(Error::new(
+            format!(
+                "Invalid level \"{}\" for {}: Expected a number from {} to {}",
+                level,
+                uppercase_first(name),
+                min,
+                max
+            ),
+            ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+        )),
+    }
+}
+
+// Parses a comma separated list of (partial) names followed by a level
+fn parse_entries(
+    input: &str,
+    kind: &str,
+    levels: (i64, i64),
+    dsa_data: &DSAData,
+) -> Result<Vec<(String, i64)>, Error> {
+    let mut entries: Vec<(String, i64)> = Vec::new();
+    if input == SKIP_INPUT {
+        return Ok(entries);
+    }
+    for entry in input.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let (search, level) = match entry.rsplit_once(' ') {
+            Some(s) => s,
+            None => {
+                return Err(Error::new(
+                    format!("Missing level for \"{}\"", entry),
+                    ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                ));
+            }
+        };
+        let (name, _) = character_edit::find_entry(&[kind], search.trim(), None, dsa_data)?;
+        let level = parse_level(name, level, levels)?;
+        match entries.iter_mut().find(|(n, _)| n == name) {
+            Some(existing) => existing.1 = level,
+            None => entries.push((name.to_string(), level)),
+        }
+    }
+    Ok(entries)
+}
@@ -0,0 +1,214 @@
+use super::character::Character;
+use super::config::DSAData;
+use super::util::*;
+use clap::ArgMatches;
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The key in the character JSON under which all changes are recorded
+const CHANGELOG_KEY: &'static str = "changelog";
+
+// A change to a single value of a character
+pub struct CharacterChange {
+    pub kind: String,
+    pub name: String,
+    pub old_level: Option<i64>,
+    pub new_level: i64,
+}
+
+impl CharacterChange {
+    pub fn output(&self, character_name: &str, output: &mut impl OutputWrapper) {
+        match self.old_level {
+            Some(old_level) => output.output_line(&format!(
+                "{}: Changed {} {} from {} to {}",
+                character_name,
+                self.kind,
+                uppercase_first(&self.name),
+                old_level,
+                self.new_level
+            )),
+            None => output.output_line(&format!(
+                "{}: Added {} {} with level {}",
+                character_name,
+                self.kind,
+                uppercase_first(&self.name),
+                self.new_level
+            )),
+        }
+    }
+}
+
+// All kinds of entries that have a level and can be changed
+pub const ALL_KINDS: [&'static str; 5] = ["attribute", "talent", "technique", "spell", "chant"];
+
+/*
+Executes the "set" command on the raw character data and returns the changed data,
+which also contains a record of the change
+*/
+pub fn set_command(
+    cmd_matches: &ArgMatches,
+    raw: &[u8],
+    dsa_data: &DSAData,
+    output: &mut impl OutputWrapper,
+) -> Result<Vec<u8>, Error> {
+    let character = Character::from_raw(raw.to_vec())?;
+    let new_level: i64 = match cmd_matches.value_of("value").unwrap().parse() {
+        Ok(l) if l >= 0 => l,
+        _ => {
+            return Err(Error::new(
+                "Unable to parse value: Argument must be a non-negative integer",
+                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+            ));
+        }
+    };
+    let (name, kind) = find_entry(
+        &[cmd_matches.value_of("kind").unwrap()],
+        cmd_matches.value_of("name").unwrap(),
+        Some(&character),
+        dsa_data,
+    )?;
+
+    let mut value: Value = serde_json::from_slice(raw)?;
+    let old_level = set_level(&mut value, kind_key(kind), name, new_level)?;
+    let change = CharacterChange {
+        kind: kind.to_string(),
+        name: name.to_string(),
+        old_level,
+        new_level,
+    };
+    record_change(&mut value, &change)?;
+    change.output(character.get_name(), output);
+    Ok(serde_json::to_vec_pretty(&value)?)
+}
+
+/*
+Searches for an entry of one of the given kinds in the dsa data and the custom entries of the character.
+Returns the name and the kind of the entry
+*/
+pub fn find_entry<'a>(
+    kinds: &[&str],
+    search: &str,
+    character: Option<&'a Character>,
+    dsa_data: &'a DSAData,
+) -> Result<(&'a str, &'static str), Error> {
+    let mut entries: Vec<(&String, &'static str)> = Vec::new();
+    for kind in ALL_KINDS.iter().filter(|k| kinds.contains(k)) {
+        match *kind {
+            "attribute" => {
+                entries.extend(dsa_data.attributes.keys().map(|k| (k, "attribute")));
+            }
+            "talent" => {
+                entries.extend(dsa_data.talents.keys().map(|k| (k, "talent")));
+                if let Some(character) = character {
+                    entries.extend(character.get_custom_talents().map(|(k, _)| (k, "talent")));
+                }
+            }
+            "technique" => {
+                entries.extend(dsa_data.combat_techniques.keys().map(|k| (k, "technique")));
+                if let Some(character) = character {
+                    entries.extend(
+                        character
+                            .get_custom_techniques()
+                            .map(|(k, _, _)| (k, "technique")),
+                    );
+                }
+            }
+            "spell" => {
+                entries.extend(dsa_data.spells.keys().map(|k| (k, "spell")));
+                if let Some(character) = character {
+                    entries.extend(character.get_custom_spells().map(|(k, _)| (k, "spell")));
+                }
+            }
+            "chant" => {
+                entries.extend(dsa_data.chants.keys().map(|k| (k, "chant")));
+                if let Some(character) = character {
+                    entries.extend(character.get_custom_chants().map(|(k, _)| (k, "chant")));
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+    DSAData::match_search(entries.into_iter(), search)
+}
+
+// Returns the key of the list containing entries of the given kind in the character JSON
+pub fn kind_key(kind: &str) -> &'static str {
+    match kind {
+        "attribute" => "attributes",
+        "talent" => "skills",
+        "technique" => "combattechniques",
+        "spell" => "spells",
+        "chant" => "chants",
+        _ => unreachable!(),
+    }
+}
+
+/*
+Sets the level of the entry with the given name in the list under the given key.
+The entry is added if it doesn't exist yet. Returns the previous level
+*/
+pub fn set_level(
+    value: &mut Value,
+    key: &str,
+    name: &str,
+    level: i64,
+) -> Result<Option<i64>, Error> {
+    let entries = match value.get_mut(key) {
+        Some(Value::Array(entries)) => entries,
+        Some(_) => {
+            return Err(invalid_character_err(key));
+        }
+        None => {
+            value[key] = json!([]);
+            value[key].as_array_mut().unwrap()
+        }
+    };
+    for entry in entries.iter_mut() {
+        let entry_name = entry
+            .get("id")
+            .or_else(|| entry.get("ruleelement").and_then(|r| r.get("name")))
+            .and_then(|n| n.as_str());
+        if let Some(entry_name) = entry_name {
+            if entry_name.eq_ignore_ascii_case(name) {
+                let old_level = entry.get("level").and_then(|l| l.as_i64());
+                entry["level"] = json!(level);
+                return Ok(old_level);
+            }
+        }
+    }
+    entries.push(json!({ "id": name, "level": level }));
+    Ok(None)
+}
+
+pub fn record_change(value: &mut Value, change: &CharacterChange) -> Result<(), Error> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let record = json!({
+        "time": time,
+        "kind": change.kind,
+        "name": change.name,
+        "old": change.old_level,
+        "new": change.new_level,
+    });
+    match value.get_mut(CHANGELOG_KEY) {
+        Some(Value::Array(changelog)) => {
+            changelog.push(record);
+        }
+        Some(_) => {
+            return Err(invalid_character_err(CHANGELOG_KEY));
+        }
+        None => {
+            value[CHANGELOG_KEY] = json!([record]);
+        }
+    }
+    Ok(())
+}
+
+fn invalid_character_err(key: &str) -> Error {
+    Error::new(
+        format!("Invalid character: \"{}\" is not a list", key),
+        ErrorType::InvalidInput(InputErrorType::InvalidFormat),
+    )
+}
@@ -0,0 +1,50 @@
+use super::character::Character;
+use super::util::uppercase_first;
+use std::collections::BTreeMap;
+
+/*
+Compares the attributes and talents of two versions of a character.
+Returns a table of all values that differ, including the header row
+*/
+pub fn diff_characters(old: &Character, new: &Character) -> Vec<Vec<String>> {
+    let mut table = vec![vec![
+        String::from("Kind"),
+        String::from("Name"),
+        String::from("Old"),
+        String::from("New"),
+    ]];
+    diff_values(
+        "Attribute",
+        old.get_attributes(),
+        new.get_attributes(),
+        &mut table,
+    );
+    diff_values("Talent", old.get_skills(), new.get_skills(), &mut table);
+    table
+}
+
+fn diff_values<'a>(
+    kind: &str,
+    old: impl Iterator<Item = (&'a String, i64)>,
+    new: impl Iterator<Item = (&'a String, i64)>,
+    table: &mut Vec<Vec<String>>,
+) {
+    let mut levels: BTreeMap<&String, (Option<i64>, Option<i64>)> = BTreeMap::new();
+    for (name, level) in old {
+        levels.entry(name).or_insert((None, None)).0 = Some(level);
+    }
+    for (name, level) in new {
+        levels.entry(name).or_insert((None, None)).1 = Some(level);
+    }
+    let level_str = |level: Option<i64>| level.map_or(String::from("-"), |l| l.to_string());
+    for (name, (old_level, new_level)) in levels {
+        if old_level != new_level {
+            table.push(vec![
+                kind.to_string(),
+                uppercase_first(name),
+                level_str(old_level),
+                level_str(new_level),
+            ]);
+        }
+    }
+}
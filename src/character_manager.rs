@@ -1,30 +1,108 @@
-use crate::{config::Config, util::IOErrorType};
+use crate::config::Config;
 
 use super::{
     character::Character,
-    config,
+    character_storage::{self, CharacterStorage},
     discord_commands::CommandContext,
+    party::{GameMaster, Party, PartyMember},
     util::{Error, ErrorType, InputErrorType},
 };
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use std::{borrow::Borrow, collections::HashMap};
-use tokio::{fs, io::AsyncWriteExt};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{borrow::Borrow, collections::HashMap, fmt};
+
+mod default {
+    pub fn versions() -> Vec<super::CharacterVersion> {
+        Vec::new()
+    }
+    pub fn restored_from() -> Option<u64> {
+        None
+    }
+    pub fn guild_id() -> Option<u64> {
+        None
+    }
+    pub fn selected_guilds() -> Vec<u64> {
+        Vec::new()
+    }
+    pub fn shared_with() -> Vec<u64> {
+        Vec::new()
+    }
+    pub fn offered_to() -> Option<u64> {
+        None
+    }
+    pub fn parties() -> Vec<crate::party::Party> {
+        Vec::new()
+    }
+    pub fn game_masters() -> std::collections::HashMap<u64, crate::party::GameMaster> {
+        std::collections::HashMap::new()
+    }
+}
 
 #[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub struct CharacterId(u64);
+pub struct CharacterId(pub u64);
+
+impl fmt::Display for CharacterId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CharacterInfo {
+    pub character_id: CharacterId,
+    pub name: String,
+    // Whether the character is selected in direct messages and in guilds without a guild specific selection
+    pub selected: bool,
+    // The guild the character is bound to. Characters without a guild can be used everywhere
+    #[serde(default = "default::guild_id")]
+    pub guild_id: Option<u64>,
+    // The guilds in which the character was selected
+    #[serde(default = "default::selected_guilds")]
+    pub selected_guilds: Vec<u64>,
+    // Other users that may roll for the character, e.g. for NPCs of several game masters
+    #[serde(default = "default::shared_with")]
+    pub shared_with: Vec<u64>,
+    // The user the character was offered to, the transfer is completed once they accept it
+    #[serde(default = "default::offered_to")]
+    pub offered_to: Option<u64>,
+    // All uploaded or edited versions of the character, the last one is the current version
+    #[serde(default = "default::versions")]
+    pub versions: Vec<CharacterVersion>,
+}
+
+impl CharacterInfo {
+    /*
+    Returns true, if the character can be used in the given guild.
+    In direct messages (no guild), all characters can be used
+    */
+    pub fn is_available(&self, guild_id: Option<u64>) -> bool {
+        match (self.guild_id, guild_id) {
+            (Some(bound), Some(guild_id)) => bound == guild_id,
+            _ => true,
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize)]
-struct CharacterInfo {
-    character_id: CharacterId,
-    name: String,
-    selected: bool,
+pub struct CharacterVersion {
+    pub number: u64,
+    // The time the version was saved in seconds since the unix epoch
+    pub time: u64,
+    // The version this one was restored from, if it was created by a rollback
+    #[serde(default = "default::restored_from")]
+    pub restored_from: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
-struct CharacterList {
-    next_character_id: CharacterId,
-    characters: HashMap<u64, Vec<CharacterInfo>>,
+pub struct CharacterList {
+    pub next_character_id: CharacterId,
+    pub characters: HashMap<u64, Vec<CharacterInfo>>,
+    #[serde(default = "default::parties")]
+    pub parties: Vec<Party>,
+    // The game master of each guild that configured one
+    #[serde(default = "default::game_masters")]
+    pub game_masters: HashMap<u64, GameMaster>,
 }
 
 impl CharacterList {
@@ -32,12 +110,70 @@ impl CharacterList {
         CharacterList {
             next_character_id: CharacterId(0),
             characters: HashMap::new(),
+            parties: Vec::new(),
+            game_masters: HashMap::new(),
         }
     }
 }
 
+/*
+Keeps the most recently used characters in memory, so they don't have to be read and parsed for every command.
+If the cache is full, the least recently used character is removed
+*/
+struct CharacterCache {
+    capacity: usize,
+    // Incremented on every access, the character with the smallest value is the least recently used one
+    counter: u64,
+    characters: HashMap<u64, (Arc<Character>, u64)>,
+}
+
+impl CharacterCache {
+    fn new(capacity: usize) -> Self {
+        CharacterCache {
+            capacity,
+            counter: 0,
+            characters: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, id: CharacterId) -> Option<Arc<Character>> {
+        self.counter += 1;
+        let counter = self.counter;
+        self.characters
+            .get_mut(&id.0)
+            .map(|(character, last_used)| {
+                *last_used = counter;
+                character.clone()
+            })
+    }
+
+    fn insert(&mut self, id: CharacterId, character: Arc<Character>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.characters.contains_key(&id.0) && self.characters.len() >= self.capacity {
+            let least_recently_used = self
+                .characters
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(id, _)| *id);
+            if let Some(old_id) = least_recently_used {
+                self.characters.remove(&old_id);
+            }
+        }
+        self.counter += 1;
+        self.characters.insert(id.0, (character, self.counter));
+    }
+
+    fn remove(&mut self, id: CharacterId) {
+        self.characters.remove(&id.0);
+    }
+}
+
 pub struct CharacterManager {
     characters: CharacterList,
+    storage: Box<dyn CharacterStorage>,
+    cache: Mutex<CharacterCache>,
 }
 
 impl CharacterManager {
@@ -48,87 +184,68 @@ impl CharacterManager {
     * If there are no characters, a blank character list is created and stored
     */
     pub async fn init(config: &Config) -> Result<Self, Error> {
-        let config_path = config::get_config_dir()?;
-        let mut character_list_path = config_path.clone();
-        character_list_path.push("discord_character_list");
-        if character_list_path.exists() {
-            let data = fs::read_to_string(&character_list_path).await?;
-            Ok(CharacterManager {
-                characters: serde_json::from_str(&data)?,
-            })
-        } else {
-            let mut folder_path = config_path;
-            folder_path.push("discord_characters");
-            let mut character_manager = CharacterManager {
-                characters: CharacterList::new(),
-            };
-            if folder_path.exists() {
-                // Migrate old characters to the new storage system
-                println!("Migrating old characters to the new character storage system");
-                let mut files = fs::read_dir(&folder_path).await?;
-                let mut characters: Vec<(u64, Vec<u8>)> = Vec::new();
-                while let Some(f) = files.next_entry().await? {
-                    let os_file_name = f.file_name();
-                    let file_name = match os_file_name.to_str() {
-                        Some(s) => s,
-                        None => {
-                            return Err(Error::new(
-                                "Invalid file name encountered in discord_characters folder",
-                                ErrorType::IO(IOErrorType::Unknown),
-                            ));
-                        }
-                    };
-                    let id: u64 = match file_name.parse() {
-                        Ok(num) => num,
-                        Err(_) => {
-                            return Err(Error::new(
-                                "Unable to parse discord character file name as id",
-                                ErrorType::IO(IOErrorType::Unknown),
-                            ));
-                        }
-                    };
-                    characters.push((id, fs::read(&f.path()).await?));
+        let storage = character_storage::open_storage(&config.discord.storage)?;
+        let mut cache = CharacterCache::new(config.discord.character_cache_size);
+        match storage.read_character_list().await? {
+            Some(characters) => {
+                // Reading every character once detects corrupt data and restores backups where possible
+                for info in characters.characters.values().flatten() {
+                    match storage
+                        .read_character(info.character_id)
+                        .await
+                        .and_then(Character::from_raw)
+                    {
+                        Ok(character) => cache.insert(info.character_id, Arc::new(character)),
+                        Err(e) => println!("Error reading character \"{}\": {}", info.name, e),
+                    }
                 }
-                fs::remove_dir_all(&folder_path).await?;
-                fs::create_dir(&folder_path).await?;
-                for (id, raw_character) in characters {
+                Ok(CharacterManager {
+                    characters,
+                    storage,
+                    cache: Mutex::new(cache),
+                })
+            }
+            None => {
+                let legacy_characters = storage.take_legacy_characters().await?;
+                let mut character_manager = CharacterManager {
+                    characters: CharacterList::new(),
+                    storage,
+                    cache: Mutex::new(cache),
+                };
+                if !legacy_characters.is_empty() {
+                    // Migrate old characters to the new storage system
+                    println!("Migrating old characters to the new character storage system");
+                }
+                for (id, raw_character) in legacy_characters {
                     if let Err(e) = character_manager
-                        .add_character(id, raw_character, config)
+                        .add_character(id, None, raw_character, config)
                         .await
                     {
                         println!("Error migrating character: {}", e);
                     }
                 }
+                character_manager.write_character_list().await?;
+                Ok(character_manager)
             }
-            character_manager.write_character_list().await?;
-            Ok(character_manager)
         }
     }
 
     /*
-    Adds a character to the local storage. If a character with the same name already exists, it is replaced
+    Adds a character to the local storage. If a character with the same name and guild already exists, it is replaced.
+    Without a guild, a character keeps the guild it is bound to.
+    Every upload is kept as a version of the character. If a guild is given, the character can only be used in that guild.
     Returns a bool indicating, if a character was replaced and the character name
     */
     pub async fn add_character(
         &mut self,
         user_id: u64,
+        guild_id: Option<u64>,
         raw_character: Vec<u8>,
         config: &Config,
     ) -> Result<(bool, String), Error> {
         let id = self.characters.next_character_id;
-        let path = get_character_path(id).await?;
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&path)
-            .await?;
-        file.write_all(&raw_character).await?;
-        file.flush().await?;
-        let name = Character::from_raw(raw_character)?
-            .get_name()
-            .trim()
-            .to_string();
+        let uploaded = Arc::new(Character::from_raw(raw_character.clone())?);
+        let name = uploaded.get_name().trim().to_string();
         if name.len() > config.discord.max_name_length {
             return Err(Error::new(
                 "Character name exceeds maximum length",
@@ -137,116 +254,160 @@ impl CharacterManager {
         }
 
         if let Some(user_characters) = self.characters.characters.get_mut(&user_id) {
-            for character in user_characters.iter() {
-                // Replace a character with the same name
-                if character.name == name {
-                    let old_path = get_character_path(character.character_id).await?;
-                    fs::rename(&path, &old_path).await?;
-                    return Ok((true, name));
+            // Replace a character with the same name. Characters bound to different guilds are kept apart
+            if let Some(character) = replaced_character(user_characters, &name, guild_id)? {
+                let storage = self.storage.as_ref();
+                // Characters from before the history existed keep their old data as the first version
+                if character.versions.is_empty() {
+                    let old_data = storage.read_character(character.character_id).await?;
+                    add_version(storage, character, &old_data, None).await?;
                 }
+                storage
+                    .write_character(character.character_id, &raw_character)
+                    .await?;
+                add_version(storage, character, &raw_character, None).await?;
+                let character_id = character.character_id;
+                self.write_character_list().await?;
+                self.cache().insert(character_id, uploaded);
+                return Ok((true, name));
             }
             if user_characters.len() >= config.discord.max_num_characters {
                 return Err(Error::new("Exceeded maximum number of characters, use the \"remove\" command to free up space.", ErrorType::InvalidInput(InputErrorType::TooManyCharacters)));
             }
-            let info = CharacterInfo {
+            let mut info = CharacterInfo {
                 character_id: id,
                 name: name.clone(),
                 // Set the new character as selected if there is currently no selected character
                 selected: user_characters.iter().all(|c| !c.selected),
+                guild_id,
+                selected_guilds: Vec::new(),
+                shared_with: Vec::new(),
+                offered_to: None,
+                versions: Vec::new(),
             };
+            // A character bound to a guild is also selected there, if there is no other selected character in the guild
+            if let Some(guild_id) = guild_id {
+                if selected_character(user_characters, Some(guild_id)).is_none() {
+                    info.selected_guilds.push(guild_id);
+                }
+            }
+            self.storage.write_character(id, &raw_character).await?;
+            add_version(self.storage.as_ref(), &mut info, &raw_character, None).await?;
             user_characters.push(info);
         } else {
-            let info = CharacterInfo {
+            let mut info = CharacterInfo {
                 character_id: id,
                 name: name.clone(),
                 selected: true,
+                guild_id,
+                selected_guilds: Vec::new(),
+                shared_with: Vec::new(),
+                offered_to: None,
+                versions: Vec::new(),
             };
+            self.storage.write_character(id, &raw_character).await?;
+            add_version(self.storage.as_ref(), &mut info, &raw_character, None).await?;
             self.characters.characters.insert(user_id, vec![info]);
         }
         self.characters.next_character_id = CharacterId(id.0 + 1);
         self.write_character_list().await?;
+        self.cache().insert(id, uploaded);
         Ok((false, name))
     }
 
     /*
-    Deletes all matching characters from storage and returns a list of their names
+    Deletes all matching characters from storage and returns a list of their ids and names
     */
     pub async fn delete_character(
         &mut self,
         user_id: u64,
         name: impl Borrow<str>,
-    ) -> Result<Vec<String>, Error> {
+    ) -> Result<Vec<(CharacterId, String)>, Error> {
         let name = name.borrow().trim().to_ascii_lowercase();
         if let Some(user_characters) = self.characters.characters.get_mut(&user_id) {
-            let mut removed_names: Vec<String> = Vec::new();
+            let mut removed: Vec<(CharacterId, String)> = Vec::new();
             for c in user_characters
                 .iter()
                 .filter(|c| c.name.to_ascii_lowercase().contains(&name))
             {
-                let path = get_character_path(c.character_id).await?;
-                fs::remove_file(path).await?;
-                removed_names.push(c.name.clone());
+                self.storage.delete_character(c.character_id).await?;
+                removed.push((c.character_id, c.name.clone()));
             }
             user_characters.retain(|c| !c.name.to_ascii_lowercase().contains(&name));
+            self.remove_party_members(|_, m| removed.iter().any(|(id, _)| *id == m.character_id));
             self.write_character_list().await?;
-            Ok(removed_names)
+            let mut cache = self.cache();
+            for (id, _) in &removed {
+                cache.remove(*id);
+            }
+            Ok(removed)
         } else {
             Ok(Vec::new())
         }
     }
 
     /*
-    Returns the names for the currently selected character (if any) and all of the other characters
+    Returns the names for the currently selected character (if any) and all of the other characters,
+    that can be used in the given guild
     */
-    pub fn list_characters(&self, user_id: u64) -> (Option<String>, Vec<String>) {
+    pub fn list_characters(
+        &self,
+        user_id: u64,
+        guild_id: Option<u64>,
+    ) -> (Option<String>, Vec<String>) {
         if let Some(user_characters) = self.characters.characters.get(&user_id) {
-            let selected = user_characters
-                .iter()
-                .filter(|c| c.selected)
-                .map(|c| c.name.clone())
-                .next();
+            let selected = selected_character(user_characters, guild_id);
             let non_selected = user_characters
                 .iter()
-                .filter(|c| !c.selected)
+                .filter(|c| {
+                    c.is_available(guild_id)
+                        && selected.map_or(true, |s| s.character_id != c.character_id)
+                })
                 .map(|c| c.name.clone())
                 .collect();
-            (selected, non_selected)
+            (selected.map(|c| c.name.clone()), non_selected)
         } else {
             (None, Vec::new())
         }
     }
 
+    /*
+    Selects the character matching the given name. In a guild, the selection only applies to that guild,
+    otherwise it applies everywhere without a guild specific selection
+    */
     pub async fn select_character(
         &mut self,
         user_id: u64,
+        guild_id: Option<u64>,
         name: impl Borrow<str>,
     ) -> Result<String, Error> {
         if let Some(user_characters) = self.characters.characters.get_mut(&user_id) {
             let name = name.borrow().trim().to_ascii_lowercase();
-            let mut matching_characters = user_characters
-                .iter_mut()
-                .filter(|c| c.name.to_ascii_lowercase().contains(&name));
-            if let Some(c) = matching_characters.next() {
-                if let Some(c2) = matching_characters.next() {
-                    Err(Error::new(
-                        format!("Ambiguous name, matches \"{}\" and \"{}\"", c.name, c2.name),
-                        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
-                    ))
-                } else {
-                    let selected_id = c.character_id;
-                    let selected_name = c.name.clone();
-                    for c in user_characters {
+            let (selected_id, selected_name) =
+                match match_name(user_characters.iter(), &name, guild_id)? {
+                    Some(c) => (c.character_id, c.name.clone()),
+                    None => {
+                        return Err(Error::new(
+                            "No matching character found",
+                            ErrorType::InvalidInput(InputErrorType::MissingCharacter),
+                        ));
+                    }
+                };
+            for c in user_characters {
+                match guild_id {
+                    Some(guild_id) => {
+                        c.selected_guilds.retain(|g| *g != guild_id);
+                        if c.character_id == selected_id {
+                            c.selected_guilds.push(guild_id);
+                        }
+                    }
+                    None => {
                         c.selected = c.character_id == selected_id;
                     }
-                    self.write_character_list().await?;
-                    Ok(selected_name)
                 }
-            } else {
-                Err(Error::new(
-                    "No matching character found",
-                    ErrorType::InvalidInput(InputErrorType::MissingCharacter),
-                ))
             }
+            self.write_character_list().await?;
+            Ok(selected_name)
         } else {
             Err(Error::new(
                 "No character found for your discord account",
@@ -261,79 +422,49 @@ impl CharacterManager {
         name: Option<impl Borrow<str>>,
     ) -> Result<CharacterId, Error> {
         let sender_id = *ctx.sender()?.as_u64();
+        let guild_id = ctx.guild().map(|g| *g.as_u64());
         match name {
             None => {
-                // Use the selected character for this discord account
-                if let Some(user_characters) = self.characters.characters.get(&sender_id) {
-                    if let Some(character) = user_characters.iter().find(|c| c.selected) {
-                        Ok(character.character_id)
-                    } else {
-                        Err(Error::new(
-                            "Error getting character: No character is currently selected",
-                            ErrorType::InvalidInput(InputErrorType::MissingCharacter),
-                        ))
-                    }
-                } else {
-                    Err(Error::new(
-                        "Error getting character: No character found for this discord account",
-                        ErrorType::InvalidInput(InputErrorType::MissingCharacter),
-                    ))
-                }
+                self.find_character_for_user(sender_id, guild_id, None::<String>)
+                    .await
             }
             Some(name) => {
                 let name = name.borrow().trim().to_ascii_lowercase();
                 // First, look for a character matching the name in the senders discord account
                 if let Some(user_characters) = self.characters.characters.get(&sender_id) {
-                    let mut matching_characters = user_characters
-                        .iter()
-                        .filter(|c| c.name.to_ascii_lowercase().contains(&name));
-                    if let Some(c) = matching_characters.next() {
-                        if let Some(c2) = matching_characters.next() {
-                            return Err(Error::new(
-                                format!(
-                                    "Ambiguous character name, matches \"{}\" and \"{}\"",
-                                    c.name, c2.name
-                                ),
-                                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
-                            ));
-                        }
+                    if let Some(c) = match_name(user_characters.iter(), &name, guild_id)? {
                         return Ok(c.character_id);
                     }
                 }
-                // If no matching character was found for the user, check all the users in the channel
-                let mut matching_character: Option<&CharacterInfo> = None;
-                for user_id in ctx
-                    .members_in_channel()
-                    .await?
-                    .iter()
-                    .map(|m| m.user.id.as_u64())
-                {
-                    if let Some(user_characters) = self.characters.characters.get(user_id) {
-                        for c in user_characters.iter() {
-                            if c.name.to_ascii_lowercase().contains(&name) {
-                                if let Some(c0) = matching_character {
-                                    return Err(Error::new(
-                                        format!(
-                                            "Ambiguous character name, matches \"{}\" and \"{}\"",
-                                            c0.name, c.name
-                                        ),
-                                        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
-                                    ));
-                                } else {
-                                    matching_character = Some(c);
-                                }
-                            }
-                        }
-                    }
+                // Then the characters other users shared with the sender
+                let shared_characters = self
+                    .characters
+                    .characters
+                    .values()
+                    .flatten()
+                    .filter(|c| c.shared_with.contains(&sender_id));
+                if let Some(c) = match_name(shared_characters, &name, guild_id)? {
+                    return Ok(c.character_id);
                 }
-
-                if let Some(c) = matching_character {
-                    Ok(c.character_id)
-                } else {
-                    Err(Error::new(
+                // Game masters can also use the characters of the party in this channel
+                if !ctx.is_game_master(self).await? {
+                    return Err(Error::new(
+                        "Error getting character: No matching character found",
+                        ErrorType::InvalidInput(InputErrorType::MissingCharacter),
+                    ));
+                }
+                let channel_id = *ctx.channel()?.as_u64();
+                let party_characters = guild_id
+                    .and_then(|guild_id| self.get_party(guild_id, channel_id))
+                    .into_iter()
+                    .flat_map(|p| p.members.iter())
+                    .filter_map(|m| self.get_character_info(m.user_id, m.character_id).ok());
+                match match_name(party_characters, &name, guild_id)? {
+                    Some(c) => Ok(c.character_id),
+                    None => Err(Error::new(
                         "Error getting character: No matching character found",
                         ErrorType::InvalidInput(InputErrorType::MissingCharacter),
-                    ))
+                    )),
                 }
             }
         }
@@ -342,13 +473,14 @@ impl CharacterManager {
     pub async fn find_character_for_user(
         &self,
         user_id: u64,
+        guild_id: Option<u64>,
         name: Option<impl Borrow<str>>,
     ) -> Result<CharacterId, Error> {
         if let Some(user_characters) = self.characters.characters.get(&user_id) {
             match name {
                 None => {
                     // Use the selected character for this discord account
-                    if let Some(character) = user_characters.iter().find(|c| c.selected) {
+                    if let Some(character) = selected_character(user_characters, guild_id) {
                         Ok(character.character_id)
                     } else {
                         Err(Error::new(
@@ -360,25 +492,13 @@ impl CharacterManager {
                 Some(name) => {
                     let name = name.borrow().trim().to_ascii_lowercase();
                     // Look for a character matching the name in the specified discord account
-                    let mut matching_characters = user_characters
-                        .iter()
-                        .filter(|c| c.name.to_ascii_lowercase().contains(&name));
-                    if let Some(c) = matching_characters.next() {
-                        if let Some(c2) = matching_characters.next() {
-                            return Err(Error::new(
-                                format!(
-                                    "Ambiguous character name, matches \"{}\" and \"{}\"",
-                                    c.name, c2.name
-                                ),
-                                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
-                            ));
-                        }
-                        return Ok(c.character_id);
+                    match match_name(user_characters.iter(), &name, guild_id)? {
+                        Some(c) => Ok(c.character_id),
+                        None => Err(Error::new(
+                            "Error getting character: No matching character found",
+                            ErrorType::InvalidInput(InputErrorType::MissingCharacter),
+                        )),
                     }
-                    Err(Error::new(
-                        "Error getting character: No matching character found",
-                        ErrorType::InvalidInput(InputErrorType::MissingCharacter),
-                    ))
                 }
             }
         } else {
@@ -389,9 +509,445 @@ impl CharacterManager {
         }
     }
 
-    pub async fn get_character(&self, id: CharacterId) -> Result<Character, Error> {
-        let path = get_character_path(id).await?;
-        Ok(Character::from_file(&path).await?)
+    /*
+    Creates a new party in the given guild. Without a channel, the party is used in all channels of the guild.
+    There can only be one party per channel and one party for the whole guild
+    */
+    pub async fn create_party(
+        &mut self,
+        creator: u64,
+        guild_id: u64,
+        channel_id: Option<u64>,
+        name: impl Borrow<str>,
+    ) -> Result<String, Error> {
+        let name = name.borrow().trim().to_string();
+        if name.is_empty() {
+            return Err(Error::new(
+                "The party name must not be empty",
+                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+            ));
+        }
+        for party in self.parties(guild_id) {
+            if party.name.to_ascii_lowercase() == name.to_ascii_lowercase() {
+                return Err(Error::new(
+                    format!("There already is a party named \"{}\"", party.name),
+                    ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                ));
+            }
+            if party.channel_id == channel_id {
+                return Err(Error::new(
+                    format!(
+                        "The party \"{}\" already plays in {}",
+                        party.name,
+                        party.location()
+                    ),
+                    ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                ));
+            }
+        }
+        self.characters.parties.push(Party::new(
+            name.clone(),
+            guild_id,
+            channel_id,
+            Some(creator),
+        ));
+        self.write_character_list().await?;
+        Ok(name)
+    }
+
+    /*
+    Adds a character of the user to the matching party. A user can only play one character per party,
+    an earlier character of the user leaves the party. Returns the name of the party
+    */
+    pub async fn join_party(
+        &mut self,
+        user_id: u64,
+        guild_id: u64,
+        party_name: impl Borrow<str>,
+        character_id: CharacterId,
+    ) -> Result<String, Error> {
+        let index = self.find_party(guild_id, party_name)?;
+        let party = &mut self.characters.parties[index];
+        party.members.retain(|m| m.user_id != user_id);
+        party.members.push(PartyMember {
+            user_id,
+            character_id,
+        });
+        let party_name = party.name.clone();
+        self.write_character_list().await?;
+        Ok(party_name)
+    }
+
+    /*
+    Removes the characters of the user from the matching party. If the last member leaves, the party is removed.
+    Returns the name of the party
+    */
+    pub async fn leave_party(
+        &mut self,
+        user_id: u64,
+        guild_id: u64,
+        party_name: impl Borrow<str>,
+    ) -> Result<String, Error> {
+        let index = self.find_party(guild_id, party_name)?;
+        let party_name = self.characters.parties[index].name.clone();
+        if !self.characters.parties[index]
+            .members
+            .iter()
+            .any(|m| m.user_id == user_id)
+        {
+            return Err(Error::new(
+                format!("None of your characters is in the party \"{}\"", party_name),
+                ErrorType::InvalidInput(InputErrorType::MissingCharacter),
+            ));
+        }
+        self.remove_party_members(|p, m| {
+            p.guild_id == guild_id && p.name == party_name && m.user_id == user_id
+        });
+        self.write_character_list().await?;
+        Ok(party_name)
+    }
+
+    /*
+    Removes the matching party together with all of its members. Only the creator of the party
+    and game masters may disband it. Returns the name of the party
+    */
+    pub async fn disband_party(
+        &mut self,
+        user_id: u64,
+        is_game_master: bool,
+        guild_id: u64,
+        party_name: impl Borrow<str>,
+    ) -> Result<String, Error> {
+        let index = self.find_party(guild_id, party_name)?;
+        let party = &self.characters.parties[index];
+        if !is_game_master && party.creator != Some(user_id) {
+            return Err(Error::new(
+                format!(
+                    "Only the creator of the party \"{}\" or a game master may disband it",
+                    party.name
+                ),
+                ErrorType::InvalidInput(InputErrorType::MissingPermission),
+            ));
+        }
+        let party = self.characters.parties.remove(index);
+        self.write_character_list().await?;
+        Ok(party.name)
+    }
+
+    // Returns all parties of a guild
+    pub fn parties<'a>(&'a self, guild_id: u64) -> impl Iterator<Item = &'a Party> {
+        self.characters
+            .parties
+            .iter()
+            .filter(move |p| p.guild_id == guild_id)
+    }
+
+    // Returns the party playing in the given channel, which is either the party of the channel or of the whole guild
+    pub fn get_party(&self, guild_id: u64, channel_id: u64) -> Option<&Party> {
+        self.parties(guild_id)
+            .find(|p| p.channel_id == Some(channel_id))
+            .or_else(|| self.parties(guild_id).find(|p| p.channel_id.is_none()))
+    }
+
+    pub fn get_game_master(&self, guild_id: u64) -> Option<GameMaster> {
+        self.characters.game_masters.get(&guild_id).copied()
+    }
+
+    // Sets or removes the game master of a guild
+    pub async fn set_game_master(
+        &mut self,
+        guild_id: u64,
+        game_master: Option<GameMaster>,
+    ) -> Result<(), Error> {
+        match game_master {
+            Some(game_master) => self.characters.game_masters.insert(guild_id, game_master),
+            None => self.characters.game_masters.remove(&guild_id),
+        };
+        self.write_character_list().await
+    }
+
+    /*
+    Offers a character to another user. The character is only transferred,
+    after the receiving user accepted it with 'accept_transfer'. A character can only be offered to one user at a time.
+    Returns the name of the character and the user an earlier offer was withdrawn from, if there was one
+    */
+    pub async fn offer_transfer(
+        &mut self,
+        character_id: CharacterId,
+        receiver: u64,
+    ) -> Result<(String, Option<u64>), Error> {
+        let owner = self
+            .get_character_owner(character_id)
+            .ok_or_else(missing_character_err)?;
+        if owner == receiver {
+            return Err(Error::new(
+                "The character already belongs to this user",
+                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+            ));
+        }
+        let info = self
+            .characters
+            .characters
+            .get_mut(&owner)
+            .and_then(|c| c.iter_mut().find(|c| c.character_id == character_id))
+            .ok_or_else(missing_character_err)?;
+        let withdrawn = info.offered_to.replace(receiver).filter(|u| *u != receiver);
+        let name = info.name.clone();
+        self.write_character_list().await?;
+        Ok((name, withdrawn))
+    }
+
+    /*
+    Moves a character offered to the receiver into their account. The name is only required,
+    if several characters were offered to the receiver. The character keeps its versions and party memberships,
+    but is not selected anywhere. Returns the name of the character
+    */
+    pub async fn accept_transfer(
+        &mut self,
+        receiver: u64,
+        name: Option<&str>,
+        config: &Config,
+    ) -> Result<String, Error> {
+        let (owner, character_id) = self.find_offered_character(receiver, name)?;
+        let name = self.get_character_info(owner, character_id)?.name.clone();
+        if let Some(receiver_characters) = self.characters.characters.get(&receiver) {
+            if receiver_characters.iter().any(|c| c.name == name) {
+                return Err(Error::new(
+                    format!("You already have a character named \"{}\"", name),
+                    ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                ));
+            }
+            if receiver_characters.len() >= config.discord.max_num_characters {
+                return Err(Error::new("Exceeded maximum number of characters, use the \"remove\" command to free up space.", ErrorType::InvalidInput(InputErrorType::TooManyCharacters)));
+            }
+        }
+
+        let owner_characters = self
+            .characters
+            .characters
+            .get_mut(&owner)
+            .ok_or_else(missing_character_err)?;
+        let index = owner_characters
+            .iter()
+            .position(|c| c.character_id == character_id)
+            .ok_or_else(missing_character_err)?;
+        let mut info = owner_characters.remove(index);
+        let receiver_characters = self
+            .characters
+            .characters
+            .entry(receiver)
+            .or_insert_with(Vec::new);
+        info.selected = receiver_characters.iter().all(|c| !c.selected);
+        info.selected_guilds.clear();
+        info.shared_with.retain(|u| *u != receiver);
+        info.offered_to = None;
+        receiver_characters.push(info);
+
+        // The receiver plays the character in its parties, instead of any other character
+        for party in self.characters.parties.iter_mut() {
+            if party.members.iter().any(|m| m.character_id == character_id) {
+                party.members.retain(|m| m.user_id != receiver);
+                for member in party.members.iter_mut() {
+                    if member.character_id == character_id {
+                        member.user_id = receiver;
+                    }
+                }
+            }
+        }
+        self.write_character_list().await?;
+        Ok(name)
+    }
+
+    // Declines a character offered to the receiver and returns its name
+    pub async fn decline_transfer(
+        &mut self,
+        receiver: u64,
+        name: Option<&str>,
+    ) -> Result<String, Error> {
+        let (owner, character_id) = self.find_offered_character(receiver, name)?;
+        let info = self
+            .characters
+            .characters
+            .get_mut(&owner)
+            .and_then(|c| c.iter_mut().find(|c| c.character_id == character_id))
+            .ok_or_else(missing_character_err)?;
+        info.offered_to = None;
+        let name = info.name.clone();
+        self.write_character_list().await?;
+        Ok(name)
+    }
+
+    // Returns the owner and id of the character offered to the receiver that matches the (partial) name
+    fn find_offered_character(
+        &self,
+        receiver: u64,
+        name: Option<&str>,
+    ) -> Result<(u64, CharacterId), Error> {
+        let name = name.unwrap_or("").trim().to_ascii_lowercase();
+        let offered = self
+            .characters
+            .characters
+            .values()
+            .flatten()
+            .filter(|c| c.offered_to == Some(receiver));
+        let character_id = match match_name(offered, &name, None)? {
+            Some(c) => c.character_id,
+            None => {
+                return Err(Error::new(
+                    "No matching character was offered to you",
+                    ErrorType::InvalidInput(InputErrorType::MissingCharacter),
+                ));
+            }
+        };
+        let owner = self
+            .get_character_owner(character_id)
+            .ok_or_else(missing_character_err)?;
+        Ok((owner, character_id))
+    }
+
+    /*
+    Allows or forbids another user to roll for one of the owners characters. Returns the name of the character
+    */
+    pub async fn share_character(
+        &mut self,
+        owner: u64,
+        character_id: CharacterId,
+        user_id: u64,
+        shared: bool,
+    ) -> Result<String, Error> {
+        let info = self
+            .characters
+            .characters
+            .get_mut(&owner)
+            .and_then(|c| c.iter_mut().find(|c| c.character_id == character_id))
+            .ok_or_else(missing_character_err)?;
+        info.shared_with.retain(|u| *u != user_id);
+        if shared && user_id != owner {
+            info.shared_with.push(user_id);
+        }
+        let name = info.name.clone();
+        self.write_character_list().await?;
+        Ok(name)
+    }
+
+    // Returns the names of all characters other users shared with the user
+    pub fn list_shared_characters(&self, user_id: u64) -> Vec<String> {
+        self.characters
+            .characters
+            .values()
+            .flatten()
+            .filter(|c| c.shared_with.contains(&user_id))
+            .map(|c| c.name.clone())
+            .collect()
+    }
+
+    // Returns true, if the user owns the character or it was shared with them
+    pub fn may_use_character(&self, user_id: u64, character_id: CharacterId) -> bool {
+        match self.get_character_owner(character_id) {
+            Some(owner) if owner == user_id => true,
+            Some(owner) => self
+                .get_character_info(owner, character_id)
+                .map_or(false, |c| c.shared_with.contains(&user_id)),
+            None => false,
+        }
+    }
+
+    // Returns the id of the user that uploaded the character
+    pub fn get_character_owner(&self, character_id: CharacterId) -> Option<u64> {
+        self.characters
+            .characters
+            .iter()
+            .find(|(_, c)| c.iter().any(|c| c.character_id == character_id))
+            .map(|(user_id, _)| *user_id)
+    }
+
+    pub async fn get_character(&self, id: CharacterId) -> Result<Arc<Character>, Error> {
+        if let Some(character) = self.cache().get(id) {
+            return Ok(character);
+        }
+        let character = Arc::new(Character::from_raw(self.storage.read_character(id).await?)?);
+        self.cache().insert(id, character.clone());
+        Ok(character)
+    }
+
+    pub async fn get_raw_character(&self, id: CharacterId) -> Result<Vec<u8>, Error> {
+        self.storage.read_character(id).await
+    }
+
+    /*
+    Replaces the stored data of a character. The name of the character must not change.
+    The new data is added as a version, like an upload
+    */
+    pub async fn update_raw_character(
+        &mut self,
+        id: CharacterId,
+        raw_character: Vec<u8>,
+    ) -> Result<(), Error> {
+        let storage = self.storage.as_ref();
+        let info = self
+            .characters
+            .characters
+            .values_mut()
+            .flatten()
+            .find(|c| c.character_id == id)
+            .ok_or_else(missing_character_err)?;
+        // Characters from before the history existed keep their old data as the first version
+        if info.versions.is_empty() {
+            let old_data = storage.read_character(id).await?;
+            add_version(storage, info, &old_data, None).await?;
+        }
+        storage.write_character(id, &raw_character).await?;
+        add_version(storage, info, &raw_character, None).await?;
+        self.write_character_list().await?;
+        // The character is parsed again the next time it is used
+        self.cache().remove(id);
+        Ok(())
+    }
+
+    // Returns all versions of a character, the last one is the current version
+    pub fn get_versions<'a>(
+        &'a self,
+        user_id: u64,
+        character_id: CharacterId,
+    ) -> Result<&'a [CharacterVersion], Error> {
+        Ok(&self.get_character_info(user_id, character_id)?.versions)
+    }
+
+    pub async fn get_character_version(
+        &self,
+        user_id: u64,
+        character_id: CharacterId,
+        number: u64,
+    ) -> Result<Character, Error> {
+        let info = self.get_character_info(user_id, character_id)?;
+        Character::from_raw(get_version_data(self.storage.as_ref(), info, number).await?)
+    }
+
+    /*
+    Restores an earlier version of a character. The restored data is added as a new version,
+    so the rollback itself can be undone. Returns the number of the new version
+    */
+    pub async fn rollback_character(
+        &mut self,
+        user_id: u64,
+        character_id: CharacterId,
+        number: u64,
+    ) -> Result<u64, Error> {
+        let storage = self.storage.as_ref();
+        let info = self
+            .characters
+            .characters
+            .get_mut(&user_id)
+            .and_then(|c| c.iter_mut().find(|c| c.character_id == character_id))
+            .ok_or_else(missing_character_err)?;
+        let raw_character = get_version_data(storage, info, number).await?;
+        storage
+            .write_character(character_id, &raw_character)
+            .await?;
+        let new_number = add_version(storage, info, &raw_character, Some(number)).await?;
+        self.write_character_list().await?;
+        self.cache().remove(character_id);
+        Ok(new_number)
     }
 
     pub fn get_character_name<'a>(
@@ -420,26 +976,234 @@ impl CharacterManager {
         }
     }
 
+    fn get_character_info(
+        &self,
+        user_id: u64,
+        character_id: CharacterId,
+    ) -> Result<&CharacterInfo, Error> {
+        self.characters
+            .characters
+            .get(&user_id)
+            .and_then(|c| c.iter().find(|c| c.character_id == character_id))
+            .ok_or_else(missing_character_err)
+    }
+
+    // Returns the index of the party matching the (partial) name, an exact match is always preferred
+    fn find_party(&self, guild_id: u64, name: impl Borrow<str>) -> Result<usize, Error> {
+        let name = name.borrow().trim().to_ascii_lowercase();
+        let matching: Vec<usize> = self
+            .characters
+            .parties
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.guild_id == guild_id && p.name.to_ascii_lowercase().contains(&name))
+            .map(|(i, _)| i)
+            .collect();
+        let parties = &self.characters.parties;
+        if let Some(i) = matching
+            .iter()
+            .find(|i| parties[**i].name.to_ascii_lowercase() == name)
+        {
+            return Ok(*i);
+        }
+        match matching.as_slice() {
+            [] => Err(Error::new(
+                "No matching party found, use the \"party list\" command to see all parties",
+                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+            )),
+            [i] => Ok(*i),
+            [i, i2, ..] => Err(Error::new(
+                format!(
+                    "Ambiguous party name, matches \"{}\" and \"{}\"",
+                    parties[*i].name, parties[*i2].name
+                ),
+                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+            )),
+        }
+    }
+
+    // Removes all matching party members. Parties are removed as well, when their last member is removed
+    fn remove_party_members(&mut self, is_removed: impl Fn(&Party, &PartyMember) -> bool) {
+        let parties = std::mem::take(&mut self.characters.parties);
+        self.characters.parties = parties
+            .into_iter()
+            .filter_map(|mut party| {
+                let mut members = std::mem::take(&mut party.members);
+                let num_members = members.len();
+                members.retain(|m| !is_removed(&party, m));
+                party.members = members;
+                if num_members > 0 && party.members.is_empty() {
+                    None
+                } else {
+                    Some(party)
+                }
+            })
+            .collect();
+    }
+
+    fn cache(&self) -> MutexGuard<'_, CharacterCache> {
+        // The cache is never left in an inconsistent state, so a poisoned lock can still be used
+        match self.cache.lock() {
+            Ok(c) => c,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
     async fn write_character_list(&self) -> Result<(), Error> {
-        let mut path = config::get_config_dir()?;
-        path.push("discord_character_list");
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&path)
-            .await?;
-        let data = serde_json::to_string(&self.characters)?;
-        file.write_all(data.as_bytes()).await?;
-        file.flush().await?;
-        Ok(())
+        self.storage.write_character_list(&self.characters).await
     }
 }
 
-async fn get_character_path(character_id: CharacterId) -> Result<PathBuf, Error> {
-    let mut path = config::get_config_dir()?;
-    path.push("discord_characters");
-    fs::create_dir_all(&path).await?;
-    path.push(&character_id.0.to_string());
-    Ok(path)
+/*
+Returns the selected character of a user in the given guild. If no character was selected in the guild,
+the character that is selected everywhere else is used, as long as it is available in the guild
+*/
+fn selected_character(
+    user_characters: &[CharacterInfo],
+    guild_id: Option<u64>,
+) -> Option<&CharacterInfo> {
+    guild_id
+        .and_then(|guild_id| {
+            user_characters
+                .iter()
+                .find(|c| c.selected_guilds.contains(&guild_id) && c.is_available(Some(guild_id)))
+        })
+        .or_else(|| {
+            user_characters
+                .iter()
+                .find(|c| c.selected && c.is_available(guild_id))
+        })
+}
+
+/*
+Returns the character an upload with the given name and guild replaces. Uploads with a guild only replace the
+character bound to that guild, uploads without a guild replace an unbound character or the only bound one
+*/
+fn replaced_character<'a>(
+    user_characters: &'a mut [CharacterInfo],
+    name: &str,
+    guild_id: Option<u64>,
+) -> Result<Option<&'a mut CharacterInfo>, Error> {
+    let mut matching: Vec<&mut CharacterInfo> = user_characters
+        .iter_mut()
+        .filter(|c| c.name == name)
+        .collect();
+    if guild_id.is_some() || matching.iter().any(|c| c.guild_id.is_none()) {
+        return Ok(matching.into_iter().find(|c| c.guild_id == guild_id));
+    }
+    if matching.len() > 1 {
+        return Err(Error::new(
+            format!(
+                "There are several characters named \"{}\" for different servers, use \"--guild\" in the server of the one to replace",
+                name
+            ),
+            ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+        ));
+    }
+    Ok(matching.pop())
+}
+
+/*
+Finds the character available in the given guild whose name contains the given lowercase name.
+If the name is ambiguous, characters bound to the guild are preferred
+*/
+fn match_name<'a>(
+    characters: impl Iterator<Item = &'a CharacterInfo>,
+    name: &str,
+    guild_id: Option<u64>,
+) -> Result<Option<&'a CharacterInfo>, Error> {
+    let matching: Vec<&CharacterInfo> = characters
+        .filter(|c| c.is_available(guild_id) && c.name.to_ascii_lowercase().contains(name))
+        .collect();
+    let bound: Vec<&CharacterInfo> = matching
+        .iter()
+        .copied()
+        .filter(|c| guild_id.is_some() && c.guild_id == guild_id)
+        .collect();
+    let candidates = if bound.is_empty() { matching } else { bound };
+    match candidates.as_slice() {
+        [] => Ok(None),
+        [c] => Ok(Some(c)),
+        [c, c2, ..] => Err(Error::new(
+            format!(
+                "Ambiguous character name, matches \"{}\" and \"{}\"",
+                c.name, c2.name
+            ),
+            ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+        )),
+    }
+}
+
+// Stores the given data as the next version of a character and returns its number
+async fn add_version(
+    storage: &dyn CharacterStorage,
+    info: &mut CharacterInfo,
+    raw_character: &[u8],
+    restored_from: Option<u64>,
+) -> Result<u64, Error> {
+    let version = CharacterVersion {
+        number: info.versions.last().map_or(1, |v| v.number + 1),
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        restored_from,
+    };
+    storage
+        .write_version(info.character_id, &version, raw_character)
+        .await?;
+    let number = version.number;
+    info.versions.push(version);
+    Ok(number)
+}
+
+async fn get_version_data(
+    storage: &dyn CharacterStorage,
+    info: &CharacterInfo,
+    number: u64,
+) -> Result<Vec<u8>, Error> {
+    if !info.versions.iter().any(|v| v.number == number) {
+        return Err(Error::new(
+            format!("Version {} of \"{}\" does not exist", number, info.name),
+            ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+        ));
+    }
+    storage.read_version(info.character_id, number).await
+}
+
+fn missing_character_err() -> Error {
+    Error::new(
+        "No character found with the given id",
+        ErrorType::InvalidInput(InputErrorType::MissingCharacter),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn characters() -> Vec<CharacterInfo> {
+        serde_json::from_str(
+            r#"[
+                {"character_id": 0, "name": "Alrik", "selected": true, "guild_id": 10},
+                {"character_id": 1, "name": "Gerion", "selected": false, "guild_id": 10},
+                {"character_id": 2, "name": "Gerion", "selected": false, "guild_id": 20}
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    fn replaced(name: &str, guild_id: Option<u64>) -> Result<Option<u64>, Error> {
+        let mut characters = characters();
+        replaced_character(&mut characters, name, guild_id).map(|c| c.map(|c| c.character_id.0))
+    }
+
+    #[test]
+    fn uploads_replace_characters_of_the_same_guild() {
+        assert_eq!(replaced("Gerion", Some(20)).unwrap(), Some(2));
+        assert_eq!(replaced("Alrik", Some(20)).unwrap(), None);
+        // Without a guild, the only character with the name is replaced and stays bound
+        assert_eq!(replaced("Alrik", None).unwrap(), Some(0));
+        assert!(replaced("Gerion", None).is_err());
+    }
 }
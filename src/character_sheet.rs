@@ -0,0 +1,271 @@
+use super::character::Character;
+use super::config::DSAData;
+use super::util::*;
+use clap::ArgMatches;
+use std::collections::BTreeMap;
+
+/*
+A rendered character sheet, consisting of sections that contain a table each.
+The first row of every table is its header
+*/
+struct Sheet {
+    title: String,
+    sections: Vec<(String, Vec<Vec<String>>)>,
+}
+
+/*
+Executes the "sheet" command, which either prints the character sheet
+or attaches it as a Markdown or HTML file
+*/
+pub fn character_sheet(
+    cmd_matches: &ArgMatches,
+    character: &Character,
+    dsa_data: &DSAData,
+    output: &mut impl OutputWrapper,
+) {
+    let sheet = create_sheet(character, dsa_data);
+    match cmd_matches.value_of("export") {
+        None => {
+            output.output_line(&sheet.title);
+            for (title, table) in &sheet.sections {
+                output.new_line();
+                output.output_line(title);
+                output.output_table(table);
+            }
+        }
+        Some(format) => {
+            let data = match format {
+                "md" => render_markdown(&sheet),
+                "html" => render_html(&sheet),
+                _ => unreachable!(),
+            };
+            // Slash commands need a text response, the file is sent as a separate message
+            output.output_line(&format!("Exported sheet of {}", character.get_name()));
+            output.output_file(
+                &format!("{}.{}", file_name(character.get_name()), format),
+                data.into_bytes(),
+            );
+        }
+    }
+}
+
+fn create_sheet(character: &Character, dsa_data: &DSAData) -> Sheet {
+    let mut sections: Vec<(String, Vec<Vec<String>>)> = Vec::new();
+
+    let mut attributes = vec![vec![
+        String::from("Attribute"),
+        String::from("Short"),
+        String::from("Value"),
+    ]];
+    attributes.extend(character.get_attributes().map(|(id, level)| {
+        vec![
+            uppercase_first(id),
+            dsa_data.get_attr_short_name(id).to_string(),
+            level.to_string(),
+        ]
+    }));
+    sections.push((String::from("Attributes"), attributes));
+
+    sections.push((
+        String::from("Derived values"),
+        vec![
+            vec![String::from("Name"), String::from("Value")],
+            vec![
+                String::from("Initiative"),
+                character.get_initiative_level(dsa_data).to_string(),
+            ],
+            vec![
+                String::from("Dodge"),
+                character.get_dodge_level(dsa_data).to_string(),
+            ],
+        ],
+    ));
+
+    // The talents are grouped by their category, custom talents form their own category
+    let mut categories: BTreeMap<&str, Vec<(&String, &Vec<String>)>> = BTreeMap::new();
+    for (name, talent) in &dsa_data.talents {
+        categories
+            .entry(&talent.category)
+            .or_insert_with(Vec::new)
+            .push((name, &talent.attributes));
+    }
+    for (name, attributes) in character.get_custom_talents() {
+        categories
+            .entry("Custom")
+            .or_insert_with(Vec::new)
+            .push((name, attributes));
+    }
+    for (category, mut talents) in categories {
+        talents.sort_by(|(name1, _), (name2, _)| name1.cmp(name2));
+        let mut table = vec![vec![
+            String::from("Talent"),
+            String::from("Check"),
+            String::from("Level"),
+        ]];
+        table.extend(talents.into_iter().map(|(name, attributes)| {
+            vec![
+                uppercase_first(name),
+                check_attributes(attributes, dsa_data),
+                character.get_skill_level(name).to_string(),
+            ]
+        }));
+        sections.push((format!("Talents: {}", category), table));
+    }
+
+    let mut techniques: Vec<(&String, bool, &Vec<String>)> = dsa_data
+        .combat_techniques
+        .iter()
+        .map(|(name, technique)| (name, technique.ranged, &technique.attributes))
+        .chain(character.get_custom_techniques())
+        .collect();
+    techniques.sort_by(|(name1, _, _), (name2, _, _)| name1.cmp(name2));
+    let mut combat = vec![vec![
+        String::from("Technique"),
+        String::from("Level"),
+        String::from("AT"),
+        String::from("PA"),
+    ]];
+    combat.extend(techniques.into_iter().map(|(name, ranged, attributes)| {
+        vec![
+            uppercase_first(name),
+            character.get_technique_level(name).to_string(),
+            character.get_attack_level(name, ranged).to_string(),
+            // Ranged techniques can't be used to parry
+            if ranged {
+                String::from("-")
+            } else {
+                character
+                    .get_parry_level(name, attributes, dsa_data)
+                    .to_string()
+            },
+        ]
+    }));
+    sections.push((String::from("Combat techniques"), combat));
+
+    let spells: Vec<Vec<String>> = character
+        .get_spells()
+        .map(|(name, level)| {
+            let attributes = dsa_data
+                .spells
+                .get(name)
+                .map(|s| &s.attributes)
+                .or_else(|| {
+                    character
+                        .get_custom_spells()
+                        .find(|(custom_name, _)| *custom_name == name)
+                        .map(|(_, attributes)| attributes)
+                });
+            skill_row(name, attributes, level, dsa_data)
+        })
+        .collect();
+    if !spells.is_empty() {
+        let mut table = vec![vec![
+            String::from("Spell"),
+            String::from("Check"),
+            String::from("Level"),
+        ]];
+        table.extend(spells);
+        sections.push((String::from("Spells"), table));
+    }
+
+    let chants: Vec<Vec<String>> = character
+        .get_chants()
+        .map(|(name, level)| {
+            let attributes = dsa_data
+                .chants
+                .get(name)
+                .map(|c| &c.attributes)
+                .or_else(|| {
+                    character
+                        .get_custom_chants()
+                        .find(|(custom_name, _)| *custom_name == name)
+                        .map(|(_, attributes)| attributes)
+                });
+            skill_row(name, attributes, level, dsa_data)
+        })
+        .collect();
+    if !chants.is_empty() {
+        let mut table = vec![vec![
+            String::from("Chant"),
+            String::from("Check"),
+            String::from("Level"),
+        ]];
+        table.extend(chants);
+        sections.push((String::from("Chants"), table));
+    }
+
+    Sheet {
+        title: character.get_name().to_string(),
+        sections,
+    }
+}
+
+fn skill_row(
+    name: &str,
+    attributes: Option<&Vec<String>>,
+    level: Option<i64>,
+    dsa_data: &DSAData,
+) -> Vec<String> {
+    vec![
+        uppercase_first(name),
+        attributes.map_or(String::from("?"), |a| check_attributes(a, dsa_data)),
+        level.map_or(String::from("-"), |l| l.to_string()),
+    ]
+}
+
+// Formats the attributes of a check, e.g. "MU/IN/GE"
+fn check_attributes(attributes: &[String], dsa_data: &DSAData) -> String {
+    attributes
+        .iter()
+        .map(|a| dsa_data.get_attr_short_name(a))
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+fn render_markdown(sheet: &Sheet) -> String {
+    let escape = |s: &str| s.replace('|', "\\|");
+    let mut md = format!("# {}\n", sheet.title);
+    for (title, table) in &sheet.sections {
+        md.push_str(&format!("\n## {}\n\n", title));
+        for (i, row) in table.iter().enumerate() {
+            md.push_str(&format!(
+                "| {} |\n",
+                row.iter()
+                    .map(|entry| escape(entry))
+                    .collect::<Vec<String>>()
+                    .join(" | ")
+            ));
+            if i == 0 {
+                md.push_str(&format!("|{}\n", "---|".repeat(row.len())));
+            }
+        }
+    }
+    md
+}
+
+fn render_html(sheet: &Sheet) -> String {
+    let escape = |s: &str| {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    };
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n</head>\n<body>\n<h1>{0}</h1>\n",
+        escape(&sheet.title)
+    );
+    for (title, table) in &sheet.sections {
+        html.push_str(&format!("<h2>{}</h2>\n<table>\n", escape(title)));
+        for (i, row) in table.iter().enumerate() {
+            let tag = if i == 0 { "th" } else { "td" };
+            html.push_str("<tr>");
+            for entry in row {
+                html.push_str(&format!("<{0}>{1}</{0}>", tag, escape(entry)));
+            }
+            html.push_str("</tr>\n");
+        }
+        html.push_str("</table>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
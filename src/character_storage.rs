@@ -0,0 +1,864 @@
+use super::character::Character;
+use super::character_manager::{CharacterId, CharacterInfo, CharacterList, CharacterVersion};
+use super::config::{self, ConfigStorageType};
+use super::party::{GameMaster, Party, PartyMember};
+use super::util::{
+    read_file_recovering_async, remove_file_atomic_async, write_file_atomic_async, Error,
+    ErrorType, IOErrorType, InputErrorType,
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use serenity::async_trait;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::fs;
+
+const CHARACTER_LIST_FILE: &'static str = "discord_character_list";
+const CHARACTER_FOLDER: &'static str = "discord_characters";
+const HISTORY_FOLDER: &'static str = "discord_character_history";
+const SQLITE_FILE: &'static str = "discord_characters.sqlite";
+
+/*
+The place where the discord bot keeps its characters. The character list is managed by the 'CharacterManager',
+the storage only persists it together with the data of all characters and their versions
+*/
+#[async_trait]
+pub trait CharacterStorage: Send + Sync {
+    // Returns the stored character list or None, if no list has been stored yet
+    async fn read_character_list(&self) -> Result<Option<CharacterList>, Error>;
+    async fn write_character_list(&self, list: &CharacterList) -> Result<(), Error>;
+    async fn read_character(&self, id: CharacterId) -> Result<Vec<u8>, Error>;
+    async fn write_character(&self, id: CharacterId, raw_character: &[u8]) -> Result<(), Error>;
+    // Deletes a character together with all of its versions
+    async fn delete_character(&self, id: CharacterId) -> Result<(), Error>;
+    async fn read_version(&self, id: CharacterId, number: u64) -> Result<Vec<u8>, Error>;
+    async fn write_version(
+        &self,
+        id: CharacterId,
+        version: &CharacterVersion,
+        raw_character: &[u8],
+    ) -> Result<(), Error>;
+
+    /*
+    Removes and returns characters stored in the format from before the character list existed,
+    together with the id of the user they belong to
+    */
+    async fn take_legacy_characters(&self) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+        Ok(Vec::new())
+    }
+}
+
+pub fn open_storage(storage_type: &ConfigStorageType) -> Result<Box<dyn CharacterStorage>, Error> {
+    match storage_type {
+        ConfigStorageType::File => Ok(Box::new(FileStorage {
+            base_path: config::get_config_dir()?,
+        })),
+        ConfigStorageType::SQLite => {
+            let mut path = config::get_config_dir()?;
+            path.push(SQLITE_FILE);
+            Ok(Box::new(SQLiteStorage::open(&path)?))
+        }
+    }
+}
+
+/*
+Copies all characters and their versions from one storage to another.
+Returns the number of copied characters
+*/
+pub async fn migrate_storage(
+    from: &dyn CharacterStorage,
+    to: &dyn CharacterStorage,
+) -> Result<usize, Error> {
+    let list = match from.read_character_list().await? {
+        Some(list) => list,
+        None => {
+            return Err(Error::new(
+                "The source storage contains no characters",
+                ErrorType::InvalidInput(InputErrorType::MissingCharacter),
+            ));
+        }
+    };
+    let mut num_characters = 0;
+    for info in list.characters.values().flatten() {
+        let id = info.character_id;
+        to.write_character(id, &from.read_character(id).await?)
+            .await?;
+        for version in &info.versions {
+            to.write_version(id, version, &from.read_version(id, version.number).await?)
+                .await?;
+        }
+        num_characters += 1;
+    }
+    to.write_character_list(&list).await?;
+    Ok(num_characters)
+}
+
+// Stores the character list as a JSON file and every character and version as a separate file
+pub struct FileStorage {
+    base_path: PathBuf,
+}
+
+impl FileStorage {
+    fn path(&self, parts: &[&str]) -> PathBuf {
+        let mut path = self.base_path.clone();
+        for part in parts {
+            path.push(part);
+        }
+        path
+    }
+
+    async fn character_path(&self, id: CharacterId) -> Result<PathBuf, Error> {
+        let path = self.path(&[CHARACTER_FOLDER]);
+        fs::create_dir_all(&path).await?;
+        Ok(self.path(&[CHARACTER_FOLDER, &id.to_string()]))
+    }
+
+    async fn history_path(&self, id: CharacterId) -> Result<PathBuf, Error> {
+        let path = self.path(&[HISTORY_FOLDER, &id.to_string()]);
+        fs::create_dir_all(&path).await?;
+        Ok(path)
+    }
+}
+
+#[async_trait]
+impl CharacterStorage for FileStorage {
+    async fn read_character_list(&self) -> Result<Option<CharacterList>, Error> {
+        let path = self.path(&[CHARACTER_LIST_FILE]);
+        let is_valid = |data: &[u8]| serde_json::from_slice::<CharacterList>(data).is_ok();
+        match read_file_recovering_async(path, is_valid).await? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn write_character_list(&self, list: &CharacterList) -> Result<(), Error> {
+        let data = serde_json::to_vec(list)?;
+        write_file_atomic_async(self.path(&[CHARACTER_LIST_FILE]), data).await
+    }
+
+    async fn read_character(&self, id: CharacterId) -> Result<Vec<u8>, Error> {
+        let is_valid = |data: &[u8]| Character::from_raw(data.to_vec()).is_ok();
+        match read_file_recovering_async(self.character_path(id).await?, is_valid).await? {
+            Some(data) => Ok(data),
+            None => Err(missing_data_err(id)),
+        }
+    }
+
+    async fn write_character(&self, id: CharacterId, raw_character: &[u8]) -> Result<(), Error> {
+        write_file_atomic_async(self.character_path(id).await?, raw_character.to_vec()).await
+    }
+
+    async fn delete_character(&self, id: CharacterId) -> Result<(), Error> {
+        remove_file_atomic_async(self.character_path(id).await?).await?;
+        fs::remove_dir_all(self.history_path(id).await?).await?;
+        Ok(())
+    }
+
+    async fn read_version(&self, id: CharacterId, number: u64) -> Result<Vec<u8>, Error> {
+        let mut path = self.history_path(id).await?;
+        path.push(&number.to_string());
+        Ok(fs::read(&path).await?)
+    }
+
+    async fn write_version(
+        &self,
+        id: CharacterId,
+        version: &CharacterVersion,
+        raw_character: &[u8],
+    ) -> Result<(), Error> {
+        let mut path = self.history_path(id).await?;
+        path.push(&version.number.to_string());
+        write_file_atomic_async(path, raw_character.to_vec()).await
+    }
+
+    async fn take_legacy_characters(&self) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+        let folder_path = self.path(&[CHARACTER_FOLDER]);
+        let mut characters: Vec<(u64, Vec<u8>)> = Vec::new();
+        if self.path(&[CHARACTER_LIST_FILE]).exists() || !folder_path.exists() {
+            return Ok(characters);
+        }
+        let mut files = fs::read_dir(&folder_path).await?;
+        while let Some(f) = files.next_entry().await? {
+            let os_file_name = f.file_name();
+            let file_name = match os_file_name.to_str() {
+                Some(s) => s,
+                None => {
+                    return Err(Error::new(
+                        "Invalid file name encountered in discord_characters folder",
+                        ErrorType::IO(IOErrorType::Unknown),
+                    ));
+                }
+            };
+            // Skip backups and temporary files
+            if file_name.starts_with('.') || file_name.ends_with(".bak") {
+                continue;
+            }
+            let id: u64 = match file_name.parse() {
+                Ok(num) => num,
+                Err(_) => {
+                    return Err(Error::new(
+                        "Unable to parse discord character file name as id",
+                        ErrorType::IO(IOErrorType::Unknown),
+                    ));
+                }
+            };
+            characters.push((id, fs::read(&f.path()).await?));
+        }
+        fs::remove_dir_all(&folder_path).await?;
+        fs::create_dir(&folder_path).await?;
+        Ok(characters)
+    }
+}
+
+/*
+Stores everything in a single SQLite database. Changes to the character list are written in one transaction,
+so the list can't be left in a partially written state. Only the rows that differ from the stored list
+are inserted, updated or deleted. Since rusqlite blocks, all queries run on tokio's blocking thread pool
+*/
+pub struct SQLiteStorage {
+    state: Arc<Mutex<SQLiteState>>,
+}
+
+struct SQLiteState {
+    connection: Connection,
+    // The rows of the character list as they are currently stored, None if they have to be read again
+    list_rows: Option<ListRows>,
+}
+
+/*
+The character list split into the rows of its tables, mapped from their keys to the remaining columns.
+Sets like the selected guilds have no columns besides their keys
+*/
+#[derive(Default)]
+struct ListRows {
+    // (user_id, name, selected, position, guild_id, offered_to)
+    character_info: HashMap<i64, (i64, String, bool, i64, Option<i64>, Option<i64>)>,
+    selected_guilds: HashMap<(i64, i64), ()>,
+    shared_characters: HashMap<(i64, i64), ()>,
+    // (channel_id, position, creator)
+    parties: HashMap<(i64, String), (Option<i64>, i64, Option<i64>)>,
+    // (user_id, position)
+    party_members: HashMap<(i64, String, i64), (i64, i64)>,
+    game_masters: HashMap<i64, (&'static str, i64)>,
+}
+
+impl ListRows {
+    fn from_list(list: &CharacterList) -> ListRows {
+        let mut rows = ListRows::default();
+        for (user_id, user_characters) in &list.characters {
+            for (position, info) in user_characters.iter().enumerate() {
+                let character_id = info.character_id.0 as i64;
+                rows.character_info.insert(
+                    character_id,
+                    (
+                        *user_id as i64,
+                        info.name.clone(),
+                        info.selected,
+                        position as i64,
+                        info.guild_id.map(|id| id as i64),
+                        info.offered_to.map(|id| id as i64),
+                    ),
+                );
+                for guild_id in &info.selected_guilds {
+                    rows.selected_guilds
+                        .insert((character_id, *guild_id as i64), ());
+                }
+                for user_id in &info.shared_with {
+                    rows.shared_characters
+                        .insert((character_id, *user_id as i64), ());
+                }
+            }
+        }
+        for (position, party) in list.parties.iter().enumerate() {
+            let guild_id = party.guild_id as i64;
+            rows.parties.insert(
+                (guild_id, party.name.clone()),
+                (
+                    party.channel_id.map(|id| id as i64),
+                    position as i64,
+                    party.creator.map(|id| id as i64),
+                ),
+            );
+            for (position, member) in party.members.iter().enumerate() {
+                rows.party_members.insert(
+                    (guild_id, party.name.clone(), member.character_id.0 as i64),
+                    (member.user_id as i64, position as i64),
+                );
+            }
+        }
+        for (guild_id, game_master) in &list.game_masters {
+            let row = match game_master {
+                GameMaster::User(id) => ("User", *id as i64),
+                GameMaster::Role(id) => ("Role", *id as i64),
+            };
+            rows.game_masters.insert(*guild_id as i64, row);
+        }
+        rows
+    }
+
+    // Deletes and inserts the rows of all tables that differ between the stored rows and these
+    fn write_changes(&self, stored: &ListRows, connection: &Connection) -> Result<(), Error> {
+        write_changed_rows(
+            &stored.character_info,
+            &self.character_info,
+            |id| {
+                connection.execute(
+                    "DELETE FROM character_info WHERE character_id = ?1",
+                    params![id],
+                )
+            },
+            |id, (user_id, name, selected, position, guild_id, offered_to)| {
+                connection.execute(
+                    "INSERT INTO character_info (character_id, user_id, name, selected, position, guild_id, offered_to) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![id, user_id, name, selected, position, guild_id, offered_to],
+                )
+            },
+        )?;
+        write_changed_rows(
+            &stored.selected_guilds,
+            &self.selected_guilds,
+            |(character_id, guild_id)| {
+                connection.execute(
+                    "DELETE FROM selected_guilds WHERE character_id = ?1 AND guild_id = ?2",
+                    params![character_id, guild_id],
+                )
+            },
+            |(character_id, guild_id), _| {
+                connection.execute(
+                    "INSERT INTO selected_guilds (character_id, guild_id) VALUES (?1, ?2)",
+                    params![character_id, guild_id],
+                )
+            },
+        )?;
+        write_changed_rows(
+            &stored.shared_characters,
+            &self.shared_characters,
+            |(character_id, user_id)| {
+                connection.execute(
+                    "DELETE FROM shared_characters WHERE character_id = ?1 AND user_id = ?2",
+                    params![character_id, user_id],
+                )
+            },
+            |(character_id, user_id), _| {
+                connection.execute(
+                    "INSERT INTO shared_characters (character_id, user_id) VALUES (?1, ?2)",
+                    params![character_id, user_id],
+                )
+            },
+        )?;
+        write_changed_rows(
+            &stored.parties,
+            &self.parties,
+            |(guild_id, name)| {
+                connection.execute(
+                    "DELETE FROM parties WHERE guild_id = ?1 AND name = ?2",
+                    params![guild_id, name],
+                )
+            },
+            |(guild_id, name), (channel_id, position, creator)| {
+                connection.execute(
+                    "INSERT INTO parties (guild_id, name, channel_id, position, creator) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![guild_id, name, channel_id, position, creator],
+                )
+            },
+        )?;
+        write_changed_rows(
+            &stored.party_members,
+            &self.party_members,
+            |(guild_id, party_name, character_id)| {
+                connection.execute(
+                    "DELETE FROM party_members WHERE guild_id = ?1 AND party_name = ?2 AND character_id = ?3",
+                    params![guild_id, party_name, character_id],
+                )
+            },
+            |(guild_id, party_name, character_id), (user_id, position)| {
+                connection.execute(
+                    "INSERT INTO party_members (guild_id, party_name, user_id, character_id, position) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![guild_id, party_name, user_id, character_id, position],
+                )
+            },
+        )?;
+        write_changed_rows(
+            &stored.game_masters,
+            &self.game_masters,
+            |guild_id| {
+                connection.execute(
+                    "DELETE FROM game_masters WHERE guild_id = ?1",
+                    params![guild_id],
+                )
+            },
+            |guild_id, (kind, id)| {
+                connection.execute(
+                    "INSERT INTO game_masters (guild_id, kind, id) VALUES (?1, ?2, ?3)",
+                    params![guild_id, kind, id],
+                )
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/*
+Deletes the stored rows that were removed or changed, then inserts the rows that were added or changed.
+Rows that are equal in both maps are left untouched
+*/
+fn write_changed_rows<K: Eq + Hash, V: PartialEq>(
+    stored: &HashMap<K, V>,
+    current: &HashMap<K, V>,
+    delete: impl Fn(&K) -> rusqlite::Result<usize>,
+    insert: impl Fn(&K, &V) -> rusqlite::Result<usize>,
+) -> Result<(), Error> {
+    for (key, value) in stored {
+        if current.get(key) != Some(value) {
+            delete(key)?;
+        }
+    }
+    for (key, value) in current {
+        if stored.get(key) != Some(value) {
+            insert(key, value)?;
+        }
+    }
+    Ok(())
+}
+
+impl SQLiteStorage {
+    fn open(path: &PathBuf) -> Result<SQLiteStorage, Error> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS meta (
+                key TEXT PRIMARY KEY,
+                value INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS character_info (
+                character_id INTEGER PRIMARY KEY,
+                user_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                selected INTEGER NOT NULL,
+                position INTEGER NOT NULL,
+                guild_id INTEGER,
+                offered_to INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS parties (
+                guild_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                channel_id INTEGER,
+                position INTEGER NOT NULL,
+                creator INTEGER,
+                PRIMARY KEY (guild_id, name)
+            );
+            CREATE TABLE IF NOT EXISTS shared_characters (
+                character_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                PRIMARY KEY (character_id, user_id)
+            );
+            CREATE TABLE IF NOT EXISTS game_masters (
+                guild_id INTEGER PRIMARY KEY,
+                kind TEXT NOT NULL,
+                id INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS party_members (
+                guild_id INTEGER NOT NULL,
+                party_name TEXT NOT NULL,
+                user_id INTEGER NOT NULL,
+                character_id INTEGER NOT NULL,
+                position INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS selected_guilds (
+                character_id INTEGER NOT NULL,
+                guild_id INTEGER NOT NULL,
+                PRIMARY KEY (character_id, guild_id)
+            );
+            CREATE TABLE IF NOT EXISTS characters (
+                character_id INTEGER PRIMARY KEY,
+                data BLOB NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS versions (
+                character_id INTEGER NOT NULL,
+                number INTEGER NOT NULL,
+                time INTEGER NOT NULL,
+                restored_from INTEGER,
+                data BLOB NOT NULL,
+                PRIMARY KEY (character_id, number)
+            );",
+        )?;
+        // Databases created by earlier versions lack the columns that were added later
+        for (table, column) in &[
+            ("character_info", "guild_id"),
+            ("character_info", "offered_to"),
+            ("parties", "creator"),
+        ] {
+            if connection
+                .prepare(&format!("SELECT {} FROM {} LIMIT 0", column, table))
+                .is_err()
+            {
+                connection.execute(
+                    &format!("ALTER TABLE {} ADD COLUMN {} INTEGER", table, column),
+                    params![],
+                )?;
+            }
+        }
+        Ok(SQLiteStorage {
+            state: Arc::new(Mutex::new(SQLiteState {
+                connection,
+                list_rows: None,
+            })),
+        })
+    }
+
+    // Runs the given queries on the blocking thread pool
+    async fn run<T: Send + 'static>(
+        &self,
+        queries: impl FnOnce(&mut SQLiteState) -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        let state = self.state.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            // A panic while holding the lock can't leave the database in an inconsistent state
+            let mut state = match state.lock() {
+                Ok(s) => s,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            queries(&mut state)
+        })
+        .await;
+        match result {
+            Ok(result) => result,
+            Err(e) => Err(Error::new(
+                e.to_string(),
+                ErrorType::IO(IOErrorType::Unknown),
+            )),
+        }
+    }
+}
+
+fn read_list(connection: &Connection) -> Result<Option<CharacterList>, Error> {
+    let next_character_id: Option<i64> = connection
+        .query_row(
+            "SELECT value FROM meta WHERE key = 'next_character_id'",
+            params![],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let next_character_id = match next_character_id {
+        Some(id) => CharacterId(id as u64),
+        None => {
+            return Ok(None);
+        }
+    };
+
+    let mut versions: HashMap<u64, Vec<CharacterVersion>> = HashMap::new();
+    let mut statement = connection.prepare(
+        "SELECT character_id, number, time, restored_from FROM versions ORDER BY number",
+    )?;
+    let mut rows = statement.query(params![])?;
+    while let Some(row) = rows.next()? {
+        let character_id: i64 = row.get(0)?;
+        let restored_from: Option<i64> = row.get(3)?;
+        versions
+            .entry(character_id as u64)
+            .or_insert_with(Vec::new)
+            .push(CharacterVersion {
+                number: row.get::<_, i64>(1)? as u64,
+                time: row.get::<_, i64>(2)? as u64,
+                restored_from: restored_from.map(|n| n as u64),
+            });
+    }
+
+    let mut selected_guilds: HashMap<u64, Vec<u64>> = HashMap::new();
+    let mut statement = connection.prepare("SELECT character_id, guild_id FROM selected_guilds")?;
+    let mut rows = statement.query(params![])?;
+    while let Some(row) = rows.next()? {
+        selected_guilds
+            .entry(row.get::<_, i64>(0)? as u64)
+            .or_insert_with(Vec::new)
+            .push(row.get::<_, i64>(1)? as u64);
+    }
+
+    let mut shared_with: HashMap<u64, Vec<u64>> = HashMap::new();
+    let mut statement =
+        connection.prepare("SELECT character_id, user_id FROM shared_characters")?;
+    let mut rows = statement.query(params![])?;
+    while let Some(row) = rows.next()? {
+        shared_with
+            .entry(row.get::<_, i64>(0)? as u64)
+            .or_insert_with(Vec::new)
+            .push(row.get::<_, i64>(1)? as u64);
+    }
+
+    let mut characters: HashMap<u64, Vec<CharacterInfo>> = HashMap::new();
+    let mut statement = connection.prepare(
+        "SELECT character_id, user_id, name, selected, guild_id, offered_to FROM character_info ORDER BY position",
+    )?;
+    let mut rows = statement.query(params![])?;
+    while let Some(row) = rows.next()? {
+        let character_id = row.get::<_, i64>(0)? as u64;
+        let user_id = row.get::<_, i64>(1)? as u64;
+        let guild_id: Option<i64> = row.get(4)?;
+        let offered_to: Option<i64> = row.get(5)?;
+        characters
+            .entry(user_id)
+            .or_insert_with(Vec::new)
+            .push(CharacterInfo {
+                character_id: CharacterId(character_id),
+                name: row.get(2)?,
+                selected: row.get(3)?,
+                guild_id: guild_id.map(|id| id as u64),
+                selected_guilds: selected_guilds.remove(&character_id).unwrap_or_default(),
+                shared_with: shared_with.remove(&character_id).unwrap_or_default(),
+                offered_to: offered_to.map(|id| id as u64),
+                versions: versions.remove(&character_id).unwrap_or_default(),
+            });
+    }
+
+    let mut parties: Vec<Party> = Vec::new();
+    let mut statement = connection
+        .prepare("SELECT guild_id, name, channel_id, creator FROM parties ORDER BY position")?;
+    let mut rows = statement.query(params![])?;
+    while let Some(row) = rows.next()? {
+        let channel_id: Option<i64> = row.get(2)?;
+        let creator: Option<i64> = row.get(3)?;
+        parties.push(Party::new(
+            row.get(1)?,
+            row.get::<_, i64>(0)? as u64,
+            channel_id.map(|id| id as u64),
+            creator.map(|id| id as u64),
+        ));
+    }
+    let mut statement = connection.prepare(
+        "SELECT guild_id, party_name, user_id, character_id FROM party_members ORDER BY position",
+    )?;
+    let mut rows = statement.query(params![])?;
+    while let Some(row) = rows.next()? {
+        let guild_id = row.get::<_, i64>(0)? as u64;
+        let party_name: String = row.get(1)?;
+        if let Some(party) = parties
+            .iter_mut()
+            .find(|p| p.guild_id == guild_id && p.name == party_name)
+        {
+            party.members.push(PartyMember {
+                user_id: row.get::<_, i64>(2)? as u64,
+                character_id: CharacterId(row.get::<_, i64>(3)? as u64),
+            });
+        }
+    }
+
+    let mut game_masters: HashMap<u64, GameMaster> = HashMap::new();
+    let mut statement = connection.prepare("SELECT guild_id, kind, id FROM game_masters")?;
+    let mut rows = statement.query(params![])?;
+    while let Some(row) = rows.next()? {
+        let kind: String = row.get(1)?;
+        let id = row.get::<_, i64>(2)? as u64;
+        let game_master = match kind.as_str() {
+            "User" => GameMaster::User(id),
+            _ => GameMaster::Role(id),
+        };
+        game_masters.insert(row.get::<_, i64>(0)? as u64, game_master);
+    }
+    Ok(Some(CharacterList {
+        next_character_id,
+        characters,
+        parties,
+        game_masters,
+    }))
+}
+
+#[async_trait]
+impl CharacterStorage for SQLiteStorage {
+    async fn read_character_list(&self) -> Result<Option<CharacterList>, Error> {
+        self.run(|state| {
+            let list = read_list(&state.connection)?;
+            state.list_rows = Some(match &list {
+                Some(list) => ListRows::from_list(list),
+                None => ListRows::default(),
+            });
+            Ok(list)
+        })
+        .await
+    }
+
+    async fn write_character_list(&self, list: &CharacterList) -> Result<(), Error> {
+        let next_character_id = list.next_character_id.0 as i64;
+        let rows = ListRows::from_list(list);
+        self.run(move |state| {
+            let stored = match state.list_rows.take() {
+                Some(stored) => stored,
+                None => match read_list(&state.connection)? {
+                    Some(list) => ListRows::from_list(&list),
+                    None => ListRows::default(),
+                },
+            };
+            let transaction = state.connection.transaction()?;
+            transaction.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES ('next_character_id', ?1)",
+                params![next_character_id],
+            )?;
+            rows.write_changes(&stored, &transaction)?;
+            transaction.commit()?;
+            state.list_rows = Some(rows);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn read_character(&self, id: CharacterId) -> Result<Vec<u8>, Error> {
+        self.run(move |state| {
+            let data: Option<Vec<u8>> = state
+                .connection
+                .query_row(
+                    "SELECT data FROM characters WHERE character_id = ?1",
+                    params![id.0 as i64],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            data.ok_or_else(|| missing_data_err(id))
+        })
+        .await
+    }
+
+    async fn write_character(&self, id: CharacterId, raw_character: &[u8]) -> Result<(), Error> {
+        let raw_character = raw_character.to_vec();
+        self.run(move |state| {
+            state.connection.execute(
+                "INSERT OR REPLACE INTO characters (character_id, data) VALUES (?1, ?2)",
+                params![id.0 as i64, raw_character],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_character(&self, id: CharacterId) -> Result<(), Error> {
+        self.run(move |state| {
+            let transaction = state.connection.transaction()?;
+            transaction.execute(
+                "DELETE FROM characters WHERE character_id = ?1",
+                params![id.0 as i64],
+            )?;
+            transaction.execute(
+                "DELETE FROM versions WHERE character_id = ?1",
+                params![id.0 as i64],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn read_version(&self, id: CharacterId, number: u64) -> Result<Vec<u8>, Error> {
+        self.run(move |state| {
+            let data: Option<Vec<u8>> = state
+                .connection
+                .query_row(
+                    "SELECT data FROM versions WHERE character_id = ?1 AND number = ?2",
+                    params![id.0 as i64, number as i64],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            data.ok_or_else(|| missing_data_err(id))
+        })
+        .await
+    }
+
+    async fn write_version(
+        &self,
+        id: CharacterId,
+        version: &CharacterVersion,
+        raw_character: &[u8],
+    ) -> Result<(), Error> {
+        let (number, time, restored_from) = (
+            version.number as i64,
+            version.time as i64,
+            version.restored_from.map(|n| n as i64),
+        );
+        let raw_character = raw_character.to_vec();
+        self.run(move |state| {
+            state.connection.execute(
+                "INSERT OR REPLACE INTO versions (character_id, number, time, restored_from, data) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![id.0 as i64, number, time, restored_from, raw_character],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+fn missing_data_err(id: CharacterId) -> Error {
+    Error::new(
+        format!("No data found for character {}", id),
+        ErrorType::IO(IOErrorType::MissingFile),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRST_LIST: &'static str = r#"{
+        "next_character_id": 3,
+        "characters": {
+            "1": [
+                {"character_id": 0, "name": "Alrik", "selected": true, "guild_id": null, "selected_guilds": [10], "shared_with": [2], "offered_to": 2, "versions": []},
+                {"character_id": 1, "name": "Gerion", "selected": false, "guild_id": 10, "selected_guilds": [], "shared_with": [], "offered_to": null, "versions": []}
+            ],
+            "2": [
+                {"character_id": 2, "name": "Layariel", "selected": true, "guild_id": null, "selected_guilds": [], "shared_with": [], "offered_to": null, "versions": []}
+            ]
+        },
+        "parties": [
+            {"name": "Heroes", "guild_id": 10, "channel_id": 20, "creator": 1, "members": [
+                {"user_id": 1, "character_id": 0},
+                {"user_id": 2, "character_id": 2}
+            ]},
+            {"name": "Villains", "guild_id": 10, "channel_id": null, "creator": null, "members": []}
+        ],
+        "game_masters": {"10": {"User": 2}}
+    }"#;
+
+    const SECOND_LIST: &'static str = r#"{
+        "next_character_id": 4,
+        "characters": {
+            "1": [
+                {"character_id": 1, "name": "Gerion", "selected": true, "guild_id": 10, "selected_guilds": [10], "shared_with": [], "offered_to": 2, "versions": []}
+            ],
+            "2": [
+                {"character_id": 2, "name": "Layariel", "selected": true, "guild_id": null, "selected_guilds": [], "shared_with": [1], "offered_to": null, "versions": []},
+                {"character_id": 3, "name": "Rondrian", "selected": false, "guild_id": null, "selected_guilds": [], "shared_with": [], "offered_to": null, "versions": []}
+            ]
+        },
+        "parties": [
+            {"name": "Villains", "guild_id": 10, "channel_id": 21, "creator": 2, "members": [
+                {"user_id": 2, "character_id": 3}
+            ]},
+            {"name": "Heroes", "guild_id": 10, "channel_id": 20, "creator": 1, "members": [
+                {"user_id": 2, "character_id": 2},
+                {"user_id": 1, "character_id": 1}
+            ]}
+        ],
+        "game_masters": {"10": {"Role": 30}}
+    }"#;
+
+    #[test]
+    fn sqlite_writes_changed_rows() {
+        let path = std::env::temp_dir().join(format!("dsa-cli-test-{}.sqlite", std::process::id()));
+        let storage = SQLiteStorage::open(&path).unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            for json in &[FIRST_LIST, SECOND_LIST] {
+                let list: CharacterList = serde_json::from_str(json).unwrap();
+                storage.write_character_list(&list).await.unwrap();
+                // A new connection has to read exactly the written list
+                let stored = SQLiteStorage::open(&path)
+                    .unwrap()
+                    .read_character_list()
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(
+                    serde_json::to_value(&stored).unwrap(),
+                    serde_json::from_str::<serde_json::Value>(json).unwrap()
+                );
+            }
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+}
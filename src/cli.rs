@@ -19,6 +19,24 @@ pub fn get_app() -> App<'static> {
         )
         .subcommand(App::new("unload").about("Unloads the current character, if one is loaded"))
         .subcommand(App::new("discord").about("Starts the discord bot"))
+        .subcommand(
+            App::new("migrate-storage")
+                .about("Copies all characters of the discord bot from one storage to another")
+                .arg(
+                    Arg::new("from")
+                        .about("The storage to copy the characters from")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&["File", "SQLite"]),
+                )
+                .arg(
+                    Arg::new("to")
+                        .about("The storage to copy the characters to")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&["File", "SQLite"]),
+                ),
+        )
         .subcommand(cmd_attribute_check())
         .subcommand(cmd_skillcheck())
         .subcommand(cmd_attack())
@@ -26,6 +44,13 @@ pub fn get_app() -> App<'static> {
         .subcommand(cmd_chant())
         .subcommand(cmd_dodge())
         .subcommand(cmd_parry())
+        .subcommand(cmd_extended())
+        .subcommand(cmd_sheet())
+        .subcommand(cmd_create())
+        .subcommand(cmd_set())
+        .subcommand(cmd_improve())
+        .subcommand(cmd_ap())
+        .subcommand(cmd_cost())
         .subcommand(cmd_roll())
         .subcommand(App::new("ini").about("Performs an initiative roll for the current character"))
         .subcommand(cmd_hi())
@@ -37,12 +62,17 @@ pub fn get_discord_app() -> App<'static> {
         .version(get_version())
         .subcommand(App::new("upload")
             .about("Uploads a character for your discord account. The .json file has to be attached to this message")
+            .arg(Arg::new("guild")
+                .about("Only use the character on this server")
+                .long("guild")
+                .short('g')
+            )
         )
         .subcommand(App::new("list")
             .about("Shows a list of the currently uploaded characters")
         )
         .subcommand(App::new("select")
-            .about("Selects the character containing the given (partial) name")
+            .about("Selects the character containing the given (partial) name. In a server, the selection only applies to that server")
             .arg(Arg::new("character_name")
                 .about("The name for which to search in the character list")
                 .takes_value(true)
@@ -57,15 +87,55 @@ pub fn get_discord_app() -> App<'static> {
                 .required(true)
             )
         )
-        .subcommand(cmd_attribute_check().with_discord_character_selection())
-        .subcommand(cmd_skillcheck().with_discord_character_selection())
-        .subcommand(cmd_attack().with_discord_character_selection())
-        .subcommand(cmd_spell().with_discord_character_selection())
-        .subcommand(cmd_chant().with_discord_character_selection())
-        .subcommand(cmd_parry().with_discord_character_selection())
-        .subcommand(cmd_dodge().with_discord_character_selection())
+        .subcommand(App::new("history")
+            .about("Lists all versions (uploads and edits) of one of your characters")
+            .arg(Arg::new("character_name")
+                .about("The name of the character, defaults to the selected character")
+                .takes_value(true)
+            )
+        )
+        .subcommand(App::new("diff")
+            .about("Shows the changed attributes and talents between two versions of one of your characters")
+            .arg(Arg::new("from")
+                .about("The number of the older version")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::new("to")
+                .about("The number of the newer version, defaults to the current version")
+                .takes_value(true)
+            )
+            .with_own_character_selection()
+        )
+        .subcommand(App::new("rollback")
+            .about("Restores an earlier version of one of your characters")
+            .arg(Arg::new("version")
+                .about("The number of the version to restore")
+                .takes_value(true)
+                .required(true)
+            )
+            .with_own_character_selection()
+        )
+        .subcommand(cmd_attribute_check().with_discord_character_selection().with_group_check().with_secret())
+        .subcommand(cmd_skillcheck().with_discord_character_selection().with_group_check().with_secret())
+        .subcommand(cmd_attack().with_discord_character_selection().with_group_check().with_secret())
+        .subcommand(cmd_spell().with_discord_character_selection().with_group_check().with_secret())
+        .subcommand(cmd_chant().with_discord_character_selection().with_group_check().with_secret())
+        .subcommand(cmd_parry().with_discord_character_selection().with_group_check().with_secret())
+        .subcommand(cmd_dodge().with_discord_character_selection().with_group_check().with_secret())
+        .subcommand(cmd_extended().with_discord_character_selection().with_secret())
+        .subcommand(cmd_sheet().with_discord_character_selection())
+        .subcommand(cmd_create())
+        .subcommand(cmd_set().with_edited_character_selection())
+        .subcommand(cmd_improve().with_edited_character_selection())
+        .subcommand(cmd_ap().with_edited_character_selection())
+        .subcommand(cmd_cost())
         .subcommand(cmd_roll())
-        .subcommand(App::new("rename").about("Rename all players to their respective character name")
+        .subcommand(cmd_party())
+        .subcommand(cmd_gm())
+        .subcommand(cmd_transfer())
+        .subcommand(cmd_share())
+        .subcommand(App::new("rename").about("Rename all players of the party in this channel to their respective character name")
             .arg(
                 Arg::new("reset")
                 .about("Reset player nicknames to their original names")
@@ -76,7 +146,7 @@ pub fn get_discord_app() -> App<'static> {
         .subcommand(App::new("ini").about("Performs an initiative roll for the current character")
             .arg(
                 Arg::new("all")
-                    .about("Adds the characters of all users in this server to the initiative roll")
+                    .about("Adds all characters of the party in this channel to the initiative roll")
                     .short('a')
                     .long("all")
                     .takes_value(false)
@@ -112,6 +182,10 @@ pub fn get_discord_app() -> App<'static> {
 
 trait DsaAppUtil {
     fn with_discord_character_selection(self) -> Self;
+    fn with_own_character_selection(self) -> Self;
+    fn with_edited_character_selection(self) -> Self;
+    fn with_group_check(self) -> Self;
+    fn with_secret(self) -> Self;
     fn with_simple_facilitation(self) -> Self;
     fn with_attribute_facilitation(self) -> Self;
     fn with_bonus_points(self) -> Self;
@@ -128,13 +202,62 @@ impl<'a> DsaAppUtil for App<'a> {
         )
         .arg(
             Arg::new("user_id")
-                .about("A discord user for whom to roll the check")
+                .about("A discord user for whom to roll the check, only available to game masters for characters in their party")
                 .takes_value(true)
                 .short('u')
                 .long("user"),
         )
     }
 
+    // For commands that change a character, only the characters of the sender can be selected
+    fn with_own_character_selection(self) -> Self {
+        self.arg(
+            Arg::new("character_name")
+                .about("The name of the character to use")
+                .takes_value(true)
+                .short('c')
+                .long("character"),
+        )
+    }
+
+    // Game masters can also change the characters of other users
+    fn with_edited_character_selection(self) -> Self {
+        self.with_own_character_selection().arg(
+            Arg::new("user_id")
+                .about("A discord user whose character to change, only available to game masters for characters in their party")
+                .takes_value(true)
+                .short('u')
+                .long("user"),
+        )
+    }
+
+    fn with_group_check(self) -> Self {
+        self.arg(
+            Arg::new("all")
+                .about("Rolls the check for all characters of the party in this channel")
+                .short('a')
+                .long("all")
+                .takes_value(false)
+                .conflicts_with_all(&["character_name", "user_id"]),
+        )
+    }
+
+    fn with_secret(self) -> Self {
+        self.arg(
+            Arg::new("secret")
+                .about("Sends the result only to the game master. Game masters can combine it with \"user\" to roll secretly for a player")
+                .long("secret")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("hint")
+                .about("Sends the player a vague description of the result of a secret check")
+                .long("hint")
+                .takes_value(false)
+                .requires("secret"),
+        )
+    }
+
     fn with_simple_facilitation(self) -> Self {
         self.setting(AppSettings::AllowLeadingHyphen).arg(
             Arg::new("facilitation")
@@ -198,6 +321,18 @@ fn cmd_skillcheck() -> App<'static> {
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::new("specialization")
+                .about("The (partial) name of a specialization (area of use) of the talent")
+                .takes_value(true)
+                .long("use"),
+        )
+        .arg(
+            Arg::new("routine")
+                .about("Uses a routine check without rolling, if the character qualifies")
+                .long("routine")
+                .takes_value(false),
+        )
         .with_attribute_facilitation()
         .with_bonus_points()
 }
@@ -210,8 +345,259 @@ fn cmd_attack() -> App<'static> {
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::new("maneuver")
+                .about("A combat maneuver and its level, e.g. \"wuchtschlag:2\"")
+                .long("maneuver")
+                .short('m')
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("distance")
+                .about("The distance to the target of a ranged attack")
+                .long("distance")
+                .takes_value(true)
+                .possible_values(&["close", "medium", "far"]),
+        )
+        .arg(
+            Arg::new("size")
+                .about("The size of the target of a ranged attack")
+                .long("size")
+                .takes_value(true)
+                .possible_values(&["tiny", "small", "medium", "large", "huge"]),
+        )
+        .arg(
+            Arg::new("moving")
+                .about("The target of the ranged attack is moving")
+                .long("moving")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("visibility")
+                .about("The level of bad visibility for a ranged attack (1-3)")
+                .long("visibility")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("aim")
+                .about("The number of actions spent aiming for a ranged attack (1-2)")
+                .long("aim")
+                .takes_value(true),
+        )
         .with_simple_facilitation()
 }
+fn cmd_sheet() -> App<'static> {
+    App::new("sheet")
+        .about("Shows the character sheet or exports it as a file")
+        .arg(
+            Arg::new("export")
+                .about("The file format to export the character sheet to")
+                .long("export")
+                .short('e')
+                .takes_value(true)
+                .possible_values(&["md", "html"]),
+        )
+}
+
+fn cmd_create() -> App<'static> {
+    App::new("create")
+        .about("Creates a new character step by step")
+        .arg(
+            Arg::new("ap")
+                .about("The AP budget of the new character, defaults to 1100")
+                .long("ap")
+                .takes_value(true),
+        )
+}
+
+fn cmd_set() -> App<'static> {
+    App::new("set")
+        .about("Changes the level of an attribute, talent, combat technique, spell or chant")
+        .arg(
+            Arg::new("kind")
+                .about("The kind of value to change")
+                .takes_value(true)
+                .required(true)
+                .possible_values(&["attribute", "talent", "technique", "spell", "chant"]),
+        )
+        .arg(
+            Arg::new("name")
+                .about("The (partial) name of the value to change")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::new("value")
+                .about("The new level")
+                .takes_value(true)
+                .required(true),
+        )
+}
+
+fn cmd_improve() -> App<'static> {
+    App::new("improve")
+        .about("Raises an attribute, talent, combat technique, spell or chant by spending AP")
+        .arg(
+            Arg::new("name")
+                .about("The (partial) name of the value to raise")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::new("amount")
+                .about("The number of levels to raise the value by, e.g. +2. Defaults to 1")
+                .takes_value(true)
+                .allow_hyphen_values(true),
+        )
+}
+
+fn cmd_ap() -> App<'static> {
+    App::new("ap")
+        .about("Shows the adventure points (AP) of the character or awards new AP")
+        .arg(
+            Arg::new("action")
+                .about("Whether to show the AP or to add new AP")
+                .takes_value(true)
+                .possible_values(&["show", "add"])
+                .default_value("show"),
+        )
+        .arg(
+            Arg::new("amount")
+                .about("The amount of AP to add")
+                .takes_value(true),
+        )
+}
+
+fn cmd_party() -> App<'static> {
+    App::new("party")
+        .about("Creates, joins, leaves, disbands or lists the parties of this server")
+        .arg(
+            Arg::new("action")
+                .about("What to do with the party")
+                .takes_value(true)
+                .required(true)
+                .possible_values(&["create", "join", "leave", "disband", "list"]),
+        )
+        .arg(
+            Arg::new("party_name")
+                .about("The (partial) name of the party, required for all actions except list")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("character_name")
+                .about("The character that joins the party, defaults to the selected character")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("guild")
+                .about("Creates the party for all channels of this server instead of just this channel")
+                .long("guild")
+                .short('g')
+                .takes_value(false),
+        )
+}
+
+fn cmd_gm() -> App<'static> {
+    App::new("gm")
+        .about("Shows or changes the game master of this server, who may roll for and view the characters of other users")
+        .arg(
+            Arg::new("action")
+                .about("Whether to show, set or remove the game master. Only the server owner may change it")
+                .takes_value(true)
+                .possible_values(&["show", "set", "clear"])
+                .default_value("show"),
+        )
+        .arg(
+            Arg::new("user_id")
+                .about("The user that becomes game master")
+                .takes_value(true)
+                .long("user")
+                .short('u')
+                .conflicts_with("role_id"),
+        )
+        .arg(
+            Arg::new("role_id")
+                .about("The id of the role whose members become game masters")
+                .takes_value(true)
+                .long("role")
+                .short('r'),
+        )
+}
+
+fn cmd_transfer() -> App<'static> {
+    App::new("transfer")
+        .about("Gives a character to another user, who has to accept it. Game masters can transfer the characters of other users")
+        .arg(
+            Arg::new("action")
+                .about("Whether to offer a character, or to accept or decline a character offered to you")
+                .takes_value(true)
+                .required(true)
+                .possible_values(&["offer", "accept", "decline"]),
+        )
+        .arg(
+            Arg::new("user_id")
+                .about("The user that receives the character")
+                .takes_value(true)
+                .long("user")
+                .short('u'),
+        )
+        .arg(
+            Arg::new("character_name")
+                .about("The character to offer, defaults to the selected character. When several characters were offered to you, the one to accept or decline")
+                .takes_value(true)
+                .long("character")
+                .short('c'),
+        )
+}
+
+fn cmd_share() -> App<'static> {
+    App::new("share")
+        .about("Allows another user, e.g. a co-GM, to roll for one of your characters")
+        .arg(
+            Arg::new("user_id")
+                .about("The user that may roll for the character")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::new("character_name")
+                .about("The character to share, defaults to the selected character")
+                .takes_value(true)
+                .long("character")
+                .short('c'),
+        )
+        .arg(
+            Arg::new("remove")
+                .about("Stops sharing the character with the user")
+                .long("remove")
+                .short('r')
+                .takes_value(false),
+        )
+}
+
+fn cmd_cost() -> App<'static> {
+    App::new("cost")
+        .about("Shows the AP cost of raising a value without changing the character")
+        .arg(
+            Arg::new("name")
+                .about("The (partial) name of the value")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::new("from")
+                .about("The current level")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::new("to")
+                .about("The target level")
+                .takes_value(true)
+                .required(true),
+        )
+}
+
 fn cmd_spell() -> App<'static> {
     App::new("spell")
         .about("Performs a spell skillcheck for the given spell")
@@ -254,6 +640,42 @@ fn cmd_parry() -> App<'static> {
         )
         .with_simple_facilitation()
 }
+fn cmd_extended() -> App<'static> {
+    App::new("extended")
+        .about("Starts, rolls or shows an extended check (Sammelprobe) for the given talent")
+        .arg(
+            Arg::new("action")
+                .about("The action to perform: start, roll or status")
+                .takes_value(true)
+                .possible_values(&["start", "roll", "status"])
+                .required(true),
+        )
+        .arg(
+            Arg::new("skill_name")
+                .about("The (partial) name of the talent. Required for \"start\", for \"roll\" it has to match the running check")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("target")
+                .about("The number of quality levels that have to be accumulated")
+                .takes_value(true)
+                .long("target"),
+        )
+        .arg(
+            Arg::new("attempts")
+                .about("The maximum number of attempts")
+                .takes_value(true)
+                .long("attempts"),
+        )
+        .arg(
+            Arg::new("interval")
+                .about("The in-game time between two attempts, e.g. \"1 hour\"")
+                .takes_value(true)
+                .long("interval"),
+        )
+        .with_attribute_facilitation()
+        .with_bonus_points()
+}
 fn cmd_roll() -> App<'static> {
     App::new("roll")
         .about("Rolls some dice")
@@ -3,10 +3,9 @@ use serde::{de::DeserializeOwned, Deserialize};
 use std::collections::HashMap;
 use std::env;
 use std::fs;
-use std::io::BufReader;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
-const DSA_DATA_NEWEST_VERSION: u64 = 4;
+const DSA_DATA_NEWEST_VERSION: u64 = 18;
 
 mod default {
     pub fn auto_update_dsa_data() -> bool {
@@ -34,6 +33,8 @@ mod default {
                 max_attachement_size: max_attachement_size(),
                 max_name_length: max_name_length(),
                 max_num_characters: max_num_characters(),
+                storage: storage(),
+                character_cache_size: character_cache_size(),
             }
         }
         pub fn use_slash_commands() -> bool {
@@ -57,11 +58,35 @@ mod default {
         pub fn max_num_characters() -> usize {
             5
         }
+        pub fn storage() -> super::super::ConfigStorageType {
+            super::super::ConfigStorageType::File
+        }
+        pub fn character_cache_size() -> usize {
+            100
+        }
     }
     pub mod dsa_data {
         pub fn combat_technique_ranged() -> bool {
             false
         }
+        pub fn special_ability_bonus() -> i64 {
+            0
+        }
+        pub fn special_ability_parry_techniques() -> Vec<String> {
+            Vec::new()
+        }
+        pub fn maneuver_max_level() -> i64 {
+            1
+        }
+        pub fn maneuver_modifier() -> i64 {
+            0
+        }
+        pub fn talent_category() -> String {
+            String::from("Other")
+        }
+        pub fn improvement() -> char {
+            'B'
+        }
     }
 }
 
@@ -93,6 +118,17 @@ pub struct ConfigDiscord {
     pub max_name_length: usize,
     #[serde(default = "default::discord::max_num_characters")]
     pub max_num_characters: usize,
+    #[serde(default = "default::discord::storage")]
+    pub storage: ConfigStorageType,
+    #[serde(default = "default::discord::character_cache_size")]
+    pub character_cache_size: usize,
+}
+
+// Where the discord bot stores uploaded characters
+#[derive(Deserialize)]
+pub enum ConfigStorageType {
+    File,
+    SQLite,
 }
 #[derive(Deserialize)]
 pub struct ConfigDSARules {
@@ -115,6 +151,12 @@ pub struct DSAData {
     pub combat_techniques: HashMap<String, CombatTechniqueConfig>,
     pub spells: HashMap<String, SpellConfig>,
     pub chants: HashMap<String, ChantConfig>,
+    pub advantages: HashMap<String, AdvantageConfig>,
+    pub disadvantages: HashMap<String, AdvantageConfig>,
+    pub special_abilities: HashMap<String, SpecialAbilityConfig>,
+    pub combat_maneuvers: HashMap<String, CombatManeuverConfig>,
+    // Maps the ids used by Optolith to the ids used here
+    pub optolith_ids: HashMap<String, String>,
 }
 
 #[derive(Deserialize)]
@@ -124,20 +166,74 @@ pub struct AttributeConfig {
 #[derive(Deserialize)]
 pub struct TalentConfig {
     pub attributes: Vec<String>,
+    #[serde(default = "default::dsa_data::talent_category")]
+    pub category: String,
+    // The improvement column (Steigerungsfaktor) from 'A' to 'D'
+    #[serde(default = "default::dsa_data::improvement")]
+    pub improvement: char,
 }
 #[derive(Deserialize)]
 pub struct CombatTechniqueConfig {
     pub attributes: Vec<String>,
     #[serde(default = "default::dsa_data::combat_technique_ranged")]
     pub ranged: bool,
+    #[serde(default = "default::dsa_data::improvement")]
+    pub improvement: char,
 }
 #[derive(Deserialize)]
 pub struct SpellConfig {
     pub attributes: Vec<String>,
+    #[serde(default = "default::dsa_data::improvement")]
+    pub improvement: char,
 }
 #[derive(Deserialize)]
 pub struct ChantConfig {
     pub attributes: Vec<String>,
+    #[serde(default = "default::dsa_data::improvement")]
+    pub improvement: char,
+}
+#[derive(Deserialize)]
+pub struct AdvantageConfig {
+    // A die that is rerolled in checks for the talents the (dis)advantage applies to
+    pub reroll: Option<RerollType>,
+}
+
+// The bonuses of a special ability, per level of the ability
+#[derive(Deserialize)]
+pub struct SpecialAbilityConfig {
+    #[serde(default = "default::dsa_data::special_ability_bonus")]
+    pub dodge: i64,
+    #[serde(default = "default::dsa_data::special_ability_bonus")]
+    pub initiative: i64,
+    #[serde(default = "default::dsa_data::special_ability_bonus")]
+    pub parry: i64,
+    // The combat techniques that receive the parry bonus (all techniques if empty)
+    #[serde(default = "default::dsa_data::special_ability_parry_techniques")]
+    pub parry_techniques: Vec<String>,
+}
+
+// A combat maneuver (e.g. Wuchtschlag), all modifiers are given per level of the maneuver
+#[derive(Deserialize)]
+pub struct CombatManeuverConfig {
+    // The special ability a character needs to use this maneuver
+    pub special_ability: String,
+    #[serde(default = "default::dsa_data::maneuver_max_level")]
+    pub max_level: i64,
+    #[serde(default = "default::dsa_data::maneuver_modifier")]
+    pub attack_penalty: i64,
+    #[serde(default = "default::dsa_data::maneuver_modifier")]
+    pub damage_bonus: i64,
+    // The penalty to the defense (parry or dodge) of the defender
+    #[serde(default = "default::dsa_data::maneuver_modifier")]
+    pub defense_penalty: i64,
+}
+
+#[derive(Deserialize)]
+pub enum RerollType {
+    // The worst die is rerolled and the better result counts (e.g. Begabung)
+    Worst,
+    // The best die is rerolled and the new result counts (e.g. Unfaehig)
+    Best,
 }
 
 /*
@@ -158,10 +254,10 @@ where
 
     fn read() -> Result<Self, Error> {
         let path = Self::absolute_path()?;
-        if Path::exists(&path) {
-            let file = fs::File::open(&path)?;
-            let reader = BufReader::new(file);
-            let config: Self = serde_json::from_reader(reader)?;
+        if let Some(data) =
+            read_file_recovering(&path, |data| serde_json::from_slice::<Self>(data).is_ok())?
+        {
+            let config: Self = serde_json::from_slice(&data)?;
             Ok(config)
         } else {
             Err(Error::new(
@@ -176,8 +272,7 @@ where
 
     fn create_default() -> Result<(), Error> {
         let path = Self::absolute_path()?;
-        fs::write(path, Self::DEFAULT_CONFIG)?;
-        Ok(())
+        write_file_atomic(&path, Self::DEFAULT_CONFIG.as_bytes())
     }
 
     fn get_or_create(output: &mut impl OutputWrapper) -> Result<Self, Error> {
@@ -267,8 +362,12 @@ impl DSAData {
         }
     }
 
-    pub fn get_attr_short_name<'a>(&'a self, attribute: &str) -> &'a str {
-        self.attributes.get(attribute).unwrap().short_name.as_str()
+    // Returns the short name of an attribute, or the attribute itself if it is unknown
+    pub fn get_attr_short_name<'a>(&'a self, attribute: &'a str) -> &'a str {
+        match self.attributes.get(attribute) {
+            Some(a) => a.short_name.as_str(),
+            None => attribute,
+        }
     }
 
     pub fn check_replacement_needed(
@@ -1,5 +1,6 @@
 use crate::character_manager::CharacterManager;
 
+use super::character_creation::CharacterWizard;
 use super::cli;
 use super::config::{Config, DSAData};
 use super::discord_commands;
@@ -7,21 +8,28 @@ use super::util::*;
 use serenity::{
     async_trait,
     client::bridge::gateway::GatewayIntents,
+    http::AttachmentType,
     model::{
         channel::Message,
         gateway::Ready,
-        id::{ChannelId, GuildId},
+        id::{ChannelId, GuildId, UserId},
         interactions::{ApplicationCommand, Interaction, InteractionResponseType},
     },
     prelude::*,
 };
+use std::collections::HashMap;
 use std::fmt::Write;
+use std::time::Instant;
 
 const DISCORD_MAX_MESSAGE_LENGTH: usize = 2000;
 const DISCORD_TABLE_COL_SEP: usize = 4; //The number of whitespaces between 2 table columns
 
 pub struct Handler {
     character_manager: RwLock<CharacterManager>,
+    // The character creation wizards that are currently running and the time of their last input, by user id
+    creation_wizards: Mutex<HashMap<u64, (CharacterWizard, Instant)>>,
+    // Serializes the accesses to the file all extended checks are stored in
+    extended_checks_lock: Mutex<()>,
     config: Config,
     dsa_data: DSAData,
 }
@@ -30,6 +38,8 @@ impl Handler {
     async fn new(config: Config, dsa_data: DSAData) -> Result<Handler, Error> {
         Ok(Handler {
             character_manager: RwLock::new(CharacterManager::init(&config).await?),
+            creation_wizards: Mutex::new(HashMap::new()),
+            extended_checks_lock: Mutex::new(()),
             config,
             dsa_data,
         })
@@ -83,6 +93,8 @@ impl EventHandler for Handler {
         discord_commands::execute_command(
             &matches,
             &self.character_manager,
+            &self.creation_wizards,
+            &self.extended_checks_lock,
             &cmd_context,
             &self.config,
             &self.dsa_data,
@@ -114,6 +126,8 @@ impl EventHandler for Handler {
             discord_commands::execute_command(
                 &matches,
                 &self.character_manager,
+                &self.creation_wizards,
+                &self.extended_checks_lock,
                 &cmd_context,
                 &self.config,
                 &self.dsa_data,
@@ -121,6 +135,20 @@ impl EventHandler for Handler {
             )
             .await;
             output.send(&ctx).await;
+        } else if message.guild_id.is_none() && !message.author.bot {
+            // Other direct messages are answers to the character creation wizard
+            let mut output =
+                DiscordOutputWrapper::new(DiscordOutputType::SimpleMessage(message.channel_id));
+            discord_commands::continue_character_creation(
+                &message,
+                &self.creation_wizards,
+                &self.character_manager,
+                &self.config,
+                &self.dsa_data,
+                &mut output,
+            )
+            .await;
+            output.send(&ctx).await;
         }
     }
 }
@@ -177,12 +205,16 @@ pub struct DiscordOutputWrapper<'a> {
     output_type: DiscordOutputType<'a>,
     msg_buf: String,
     msg_empty: bool,
+    // The files that are attached to the reply, as (file name, data)
+    files: Vec<(String, Vec<u8>)>,
 }
 
 pub enum DiscordOutputType<'a> {
     SimpleMessage(ChannelId),
     ReplyTo(&'a Message),
     InteractionResponse(&'a Interaction),
+    // Sends the output to each of the users as a direct message
+    DirectMessage(Vec<UserId>),
 }
 
 impl<'a> DiscordOutputWrapper<'a> {
@@ -191,10 +223,16 @@ impl<'a> DiscordOutputWrapper<'a> {
             output_type,
             msg_buf: String::from("```"),
             msg_empty: true,
+            files: Vec::new(),
         }
     }
 
     pub async fn send(&mut self, ctx: &Context) {
+        self.send_message(ctx).await;
+        self.send_files(ctx).await;
+    }
+
+    async fn send_message(&mut self, ctx: &Context) {
         if self.msg_empty {
             return;
         } else if self.msg_buf.as_bytes().len() > DISCORD_MAX_MESSAGE_LENGTH {
@@ -236,10 +274,69 @@ impl<'a> DiscordOutputWrapper<'a> {
                     println!("Error sending interaction response: {}", e);
                 }
             }
+            DiscordOutputType::DirectMessage(user_ids) => {
+                for user_id in user_ids {
+                    let result = match user_id.create_dm_channel(ctx).await {
+                        Ok(channel) => channel.id.say(ctx, &self.msg_buf).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        println!("Error sending direct message: {}", e);
+                    }
+                }
+            }
         }
         self.msg_buf = String::from("```");
         self.msg_empty = true;
     }
+
+    // Files are sent as a separate message, since interaction responses can't contain attachments
+    async fn send_files(&mut self, ctx: &Context) {
+        if self.files.is_empty() {
+            return;
+        }
+        let files: Vec<AttachmentType> = self
+            .files
+            .iter()
+            .map(|(filename, data)| AttachmentType::Bytes {
+                data: data.clone().into(),
+                filename: filename.clone(),
+            })
+            .collect();
+        self.files.clear();
+        let result = match &self.output_type {
+            DiscordOutputType::SimpleMessage(channel_id) => {
+                channel_id.send_files(&ctx.http, files, |m| m).await
+            }
+            DiscordOutputType::ReplyTo(msg) => {
+                msg.channel_id
+                    .send_files(&ctx.http, files, |m| m.reference_message(*msg))
+                    .await
+            }
+            DiscordOutputType::InteractionResponse(interaction) => match interaction.channel_id {
+                Some(channel_id) => channel_id.send_files(&ctx.http, files, |m| m).await,
+                None => {
+                    println!("Error sending files: Interaction has no channel");
+                    return;
+                }
+            },
+            DiscordOutputType::DirectMessage(user_ids) => {
+                for user_id in user_ids {
+                    let result = match user_id.create_dm_channel(ctx).await {
+                        Ok(channel) => channel.id.send_files(&ctx.http, files.clone(), |m| m).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        println!("Error sending files as direct message: {}", e);
+                    }
+                }
+                return;
+            }
+        };
+        if let Err(e) = result {
+            println!("Error sending files: {}", e);
+        }
+    }
 }
 
 impl<'a> OutputWrapper for DiscordOutputWrapper<'a> {
@@ -280,4 +377,7 @@ impl<'a> OutputWrapper for DiscordOutputWrapper<'a> {
     fn new_line(&mut self) {
         self.msg_buf.push('\n');
     }
+    fn output_file(&mut self, file_name: &str, data: Vec<u8>) {
+        self.files.push((file_name.to_string(), data));
+    }
 }
@@ -1,79 +1,190 @@
-use crate::character_manager::CharacterManager;
+use crate::character_manager::{CharacterId, CharacterManager};
 
 use super::character::Character;
+use super::character_creation::CharacterWizard;
+use super::character_edit;
+use super::character_history;
+use super::character_sheet;
 use super::config::*;
-use super::dsa;
+use super::discord::{DiscordOutputType, DiscordOutputWrapper};
+use super::dsa::{self, CheckResult};
+use super::extended_check;
+use super::improvement;
+use super::optolith;
+use super::party::GameMaster;
 use super::util::*;
 use clap::{App, Arg, ArgMatches, ArgSettings};
-use futures::stream::StreamExt;
 use serde_json::Value;
-use std::borrow::Borrow;
+use std::collections::HashMap;
 use std::iter::Iterator;
 use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use substring::Substring;
 
 use serenity::{
     async_trait,
     builder::{CreateApplicationCommand, CreateApplicationCommandOption},
     model::{
-        channel::{Attachment, ChannelType, Message},
+        channel::{Attachment, Message},
         guild::Member,
-        id::{ChannelId, UserId},
+        id::{ChannelId, GuildId, RoleId, UserId},
         interactions::ApplicationCommand,
         interactions::{ApplicationCommandOptionType, Interaction},
-        permissions::Permissions,
     },
     prelude::*,
 };
 
+// The maximum number of guild members discord returns per request
+const DISCORD_MEMBERS_PAGE_SIZE: u64 = 1000;
+// Character creation wizards without an answer for this long are discarded
+const CREATION_WIZARD_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
 #[async_trait]
 pub trait CommandContext: Sync {
     fn context<'a>(&'a self) -> &'a Context;
     fn sender(&self) -> Result<UserId, Error>;
     fn channel(&self) -> Result<ChannelId, Error>;
+    // The guild the command was sent in, None for direct messages
+    fn guild(&self) -> Option<GuildId>;
     async fn attachments<'a>(&'a self) -> Result<&'a [Attachment], Error>;
-    async fn members_in_channel(&self) -> Result<Vec<Member>, Error> {
-        let invalid_channel_err = Err(Error::new(
-            String::from("Invalid channel targeted"),
-            ErrorType::InvalidInput(InputErrorType::InvalidDiscordContext),
-        ));
-
-        let channel_id = self.channel()?;
-        let channel = channel_id.to_channel(self.context()).await?;
-
-        let channel = match channel.guild() {
-            Some(gc) => gc,
+    /*
+    Returns the discord members of the party playing in this channel, together with their characters in the party.
+    The characters of members that can't be found in the guild (e.g. because they left it) are returned separately
+    */
+    async fn party_members(
+        &self,
+        character_manager: &CharacterManager,
+    ) -> Result<(Vec<(Member, CharacterId)>, Vec<CharacterId>), Error> {
+        let guild_id = match self.guild() {
+            Some(guild_id) => guild_id,
+            None => {
+                return Err(Error::new(
+                    "Parties can only be used in server channels",
+                    ErrorType::InvalidInput(InputErrorType::InvalidDiscordContext),
+                ));
+            }
+        };
+        let party = match character_manager.get_party(*guild_id.as_u64(), *self.channel()?.as_u64())
+        {
+            Some(party) => party,
             None => {
-                return invalid_channel_err;
+                return Err(Error::new(
+                    "No party plays in this channel, use the \"party\" command to create one",
+                    ErrorType::InvalidInput(InputErrorType::InvalidDiscordContext),
+                ));
             }
         };
+        let mut members = Vec::new();
+        let mut missing = Vec::new();
+        for party_member in &party.members {
+            match guild_id
+                .member(self.context(), UserId(party_member.user_id))
+                .await
+            {
+                Ok(member) => members.push((member, party_member.character_id)),
+                Err(e) => {
+                    println!(
+                        "Error getting party member {}: {:?}",
+                        party_member.user_id, e
+                    );
+                    missing.push(party_member.character_id);
+                }
+            }
+        }
+        Ok((members, missing))
+    }
 
-        match channel.kind {
-            ChannelType::Text | ChannelType::Private => {}
-            _ => {
-                return invalid_channel_err;
+    /*
+    Returns true, if the sender has the rights of a game master in the guild of this channel.
+    The guild owner is always a game master, in direct messages nobody is
+    */
+    async fn is_game_master(&self, character_manager: &CharacterManager) -> Result<bool, Error> {
+        let guild_id = match self.guild() {
+            Some(guild_id) => guild_id,
+            None => {
+                return Ok(false);
+            }
+        };
+        let sender = self.sender()?;
+        if self.get_guild_owner().await? == Some(sender) {
+            return Ok(true);
+        }
+        match character_manager.get_game_master(*guild_id.as_u64()) {
+            None => Ok(false),
+            Some(GameMaster::User(user_id)) => Ok(*sender.as_u64() == user_id),
+            Some(GameMaster::Role(role_id)) => {
+                let member = guild_id.member(self.context(), sender).await?;
+                Ok(member.roles.contains(&RoleId(role_id)))
             }
         }
+    }
 
-        let guild = channel.guild(self.context()).await.unwrap();
-        let g_members = guild.members(self.context(), Some(1000), None).await?;
+    // Returns an error if the sender is not a game master. The action completes the sentence "Only a game master may ..."
+    async fn require_game_master(
+        &self,
+        character_manager: &CharacterManager,
+        action: &str,
+    ) -> Result<(), Error> {
+        if self.is_game_master(character_manager).await? {
+            Ok(())
+        } else {
+            Err(Error::new(
+                format!("Only a game master may {}", action),
+                ErrorType::InvalidInput(InputErrorType::MissingPermission),
+            ))
+        }
+    }
 
-        Ok(
-            futures::stream::iter(g_members.iter().map(|m| m.clone())) // fetch members in the channel message was sent in
-                .filter_map(|member| async {
-                    if guild
-                        .user_permissions_in(&channel, &member)
-                        .map(|p| p.contains(Permissions::READ_MESSAGES))
-                        .unwrap_or(false)
-                    {
-                        Some(member)
-                    } else {
-                        None
+    /*
+    Returns the users that receive the results of secret checks: The game master user, all members of the game master role
+    or the guild owner, if no game master was set
+    */
+    async fn game_master_users(
+        &self,
+        character_manager: &CharacterManager,
+    ) -> Result<Vec<UserId>, Error> {
+        let guild_id = match self.guild() {
+            Some(guild_id) => guild_id,
+            None => {
+                return Err(Error::new(
+                    "Secret checks can only be rolled in server channels",
+                    ErrorType::InvalidInput(InputErrorType::InvalidDiscordContext),
+                ));
+            }
+        };
+        let users = match character_manager.get_game_master(*guild_id.as_u64()) {
+            Some(GameMaster::User(user_id)) => vec![UserId(user_id)],
+            Some(GameMaster::Role(role_id)) => {
+                // Members can only be fetched page by page, each page starts after the last member of the previous one
+                let mut users: Vec<UserId> = Vec::new();
+                let mut after: Option<UserId> = None;
+                loop {
+                    let members = guild_id
+                        .members(self.context(), Some(DISCORD_MEMBERS_PAGE_SIZE), after)
+                        .await?;
+                    after = members.last().map(|m| m.user.id);
+                    users.extend(
+                        members
+                            .iter()
+                            .filter(|m| m.roles.contains(&RoleId(role_id)))
+                            .map(|m| m.user.id),
+                    );
+                    if (members.len() as u64) < DISCORD_MEMBERS_PAGE_SIZE {
+                        break;
                     }
-                })
-                .collect::<Vec<Member>>()
-                .await,
-        )
+                }
+                users
+            }
+            None => self.get_guild_owner().await?.into_iter().collect(),
+        };
+        if users.is_empty() {
+            return Err(Error::new(
+                "There is no game master to receive the result, use the \"gm\" command to set one",
+                ErrorType::InvalidInput(InputErrorType::MissingPermission),
+            ));
+        }
+        Ok(users)
     }
 
     async fn rename_member(&self, member: &Member, new_name: &str) -> Result<(), Error> {
@@ -130,6 +241,9 @@ impl CommandContext for MessageContext<'_> {
     fn channel(&self) -> Result<ChannelId, Error> {
         Ok(self.message.channel_id)
     }
+    fn guild(&self) -> Option<GuildId> {
+        self.message.guild_id
+    }
     async fn attachments<'a>(&'a self) -> Result<&'a [Attachment], Error> {
         Ok(&self.message.attachments)
     }
@@ -172,6 +286,9 @@ impl CommandContext for SlashCommandContext<'_> {
             |c| Ok(c),
         )
     }
+    fn guild(&self) -> Option<GuildId> {
+        self.interaction.guild_id
+    }
     async fn attachments<'a>(&'a self) -> Result<&'a [Attachment], Error> {
         Err(Error::new(
             "Downloading attachments is not yet supported for slash commands",
@@ -332,6 +449,8 @@ pub async fn parse_discord_interaction(
 pub async fn execute_command<T>(
     matches: &clap::Result<ArgMatches>,
     character_manager: &RwLock<CharacterManager>,
+    creation_wizards: &Mutex<HashMap<u64, (CharacterWizard, Instant)>>,
+    extended_checks_lock: &Mutex<()>,
     cmd_ctx: &T,
     config: &Config,
     dsa_data: &DSAData,
@@ -347,7 +466,7 @@ pub async fn execute_command<T>(
         Ok(m) => m,
     };
     match matches.subcommand() {
-        Some(("upload", _)) => {
+        Some(("upload", sub_m)) => {
             //Attachement validation
             let attachments = match cmd_ctx.attachments().await {
                 Ok(a) => a,
@@ -370,7 +489,7 @@ pub async fn execute_command<T>(
                 ));
                 return;
             }
-            let data = match attachments[0].download().await {
+            let mut data = match attachments[0].download().await {
                 Ok(d) => d,
                 Err(e) => {
                     output.output_line(&"Internal server error");
@@ -378,6 +497,33 @@ pub async fn execute_command<T>(
                     return;
                 }
             };
+            //Optolith characters are converted before they are stored
+            match optolith::import_character(&data, dsa_data) {
+                Ok(Some((converted, unmapped))) => {
+                    optolith::output_import_report(&unmapped, output);
+                    data = converted;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    output.output_line(&format!("Error importing Optolith character: {}", e));
+                    return;
+                }
+            };
+            //Character validation
+            match Character::from_raw(data.clone()) {
+                Ok(character) => {
+                    let report = character.validate(dsa_data);
+                    report.output(output);
+                    if report.is_fatal() {
+                        output.output_line(&"Character was rejected, please fix the errors above");
+                        return;
+                    }
+                }
+                Err(e) => {
+                    output.output_line(&format!("Error reading character: {}", e));
+                    return;
+                }
+            };
             let sender = match cmd_ctx.sender() {
                 Ok(s) => s,
                 Err(e) => {
@@ -386,28 +532,45 @@ pub async fn execute_command<T>(
                     return;
                 }
             };
-            match character_manager
+            // Characters uploaded with "--guild" can only be used in the guild they were uploaded in
+            let guild_id = if sub_m.is_present("guild") {
+                match cmd_ctx.guild() {
+                    Some(guild) => Some(*guild.as_u64()),
+                    None => {
+                        output.output_line(
+                            &"Characters can only be bound to a server in a server channel",
+                        );
+                        return;
+                    }
+                }
+            } else {
+                None
+            };
+            let result = character_manager
                 .write()
                 .await
-                .add_character(*sender.as_u64(), data, config)
-                .await
+                .add_character(*sender.as_u64(), guild_id, data, config)
+                .await;
+            output_added_character(result, "uploaded", output);
+        }
+
+        Some(("create", sub_m)) => {
+            if let Err(e) =
+                start_character_creation(sub_m, creation_wizards, cmd_ctx, config, dsa_data, output)
+                    .await
             {
-                Ok((true, name)) => {
-                    output.output_line(&format!("Successfully replaced character \"{}\"", name));
-                }
-                Ok((false, name)) => {
-                    output.output_line(&format!("Successfully uploaded character \"{}\"", name));
-                }
-                Err(e) => match e.err_type() {
+                match e.err_type() {
                     ErrorType::InvalidInput(_) => {
                         output.output_line(&e);
                     }
                     _ => {
-                        output.output_line(&"Internal server error");
-                        println!("Error adding character: {}", e);
+                        output.output_line(
+                            &"Internal server error while starting character creation",
+                        );
+                        println!("Error starting character creation: {:?}", e);
                     }
-                },
-            };
+                }
+            }
         }
 
         Some(("list", _)) => {
@@ -419,10 +582,10 @@ pub async fn execute_command<T>(
                     return;
                 }
             };
+            let character_manager = character_manager.read().await;
             let (selected, non_selected) = character_manager
-                .read()
-                .await
-                .list_characters(*sender.as_u64());
+                .list_characters(*sender.as_u64(), cmd_ctx.guild().map(|g| *g.as_u64()));
+            let shared = character_manager.list_shared_characters(*sender.as_u64());
             if let Some(selected) = selected {
                 output.output_line(&"Selected character:");
                 output.output_line(&selected);
@@ -441,6 +604,13 @@ pub async fn execute_command<T>(
                     output.output_line(&name);
                 }
             }
+            if !shared.is_empty() {
+                output.output_line(&"");
+                output.output_line(&"Characters shared with you:");
+                for name in shared {
+                    output.output_line(&name);
+                }
+            }
         }
 
         Some(("select", sub_m)) => {
@@ -455,7 +625,11 @@ pub async fn execute_command<T>(
             match character_manager
                 .write()
                 .await
-                .select_character(*sender.as_u64(), sub_m.value_of("character_name").unwrap())
+                .select_character(
+                    *sender.as_u64(),
+                    cmd_ctx.guild().map(|g| *g.as_u64()),
+                    sub_m.value_of("character_name").unwrap(),
+                )
                 .await
             {
                 Ok(name) => {
@@ -493,8 +667,18 @@ pub async fn execute_command<T>(
                         output.output_line(&"No character matched the given name, use the \"characters\" command to see a list of uploaded characters");
                     } else {
                         output.output_line(&"Successfully removed characters:");
-                        for c in removed_characters {
-                            output.output_line(&c);
+                        for (_, name) in &removed_characters {
+                            output.output_line(name);
+                        }
+                        let character_keys: Vec<String> = removed_characters
+                            .iter()
+                            .map(|(id, _)| format!("discord:{}", id))
+                            .collect();
+                        let _lock = extended_checks_lock.lock().await;
+                        if let Err(e) =
+                            extended_check::remove_extended_checks(&character_keys).await
+                        {
+                            println!("Error removing extended checks: {:?}", e);
                         }
                     }
                 }
@@ -505,6 +689,23 @@ pub async fn execute_command<T>(
             }
         }
 
+        Some((cmd_name, sub_m)) if ["history", "diff", "rollback"].contains(&cmd_name) => {
+            if let Err(e) =
+                character_history_command(cmd_name, sub_m, character_manager, cmd_ctx, output).await
+            {
+                match e.err_type() {
+                    ErrorType::InvalidInput(_) => {
+                        output.output_line(&e);
+                    }
+                    _ => {
+                        output
+                            .output_line(&"Internal server error while reading character history");
+                        println!("Error executing {} command: {:?}", cmd_name, e);
+                    }
+                }
+            }
+        }
+
         Some(("attribute", sub_m)) => {
             execute_character_command(
                 &dsa::attribute_check,
@@ -589,12 +790,131 @@ pub async fn execute_command<T>(
             )
             .await;
         }
+        Some(("extended", sub_m)) => {
+            if let Err(e) = extended_command(
+                sub_m,
+                character_manager.read().await.deref(),
+                extended_checks_lock,
+                dsa_data,
+                config,
+                cmd_ctx,
+                output,
+            )
+            .await
+            {
+                match e.err_type() {
+                    ErrorType::InvalidInput(_) => {
+                        output.output_line(&e);
+                    }
+                    _ => {
+                        output.output_line(&"Internal server error during extended check");
+                        println!("Error during extended check: {:?}", e);
+                    }
+                }
+            }
+        }
+        Some(("sheet", sub_m)) => {
+            let character_manager = character_manager.read().await;
+            if let Some((character_id, character)) =
+                get_command_character(sub_m, character_manager.deref(), cmd_ctx, output).await
+            {
+                // Only the owner, the users the character was shared with and game masters of its party see its sheet
+                let may_use = cmd_ctx.sender().map_or(false, |s| {
+                    character_manager.may_use_character(*s.as_u64(), character_id)
+                });
+                if !may_use {
+                    let action = "view the sheets of other users' characters";
+                    let permission = match cmd_ctx
+                        .require_game_master(character_manager.deref(), action)
+                        .await
+                    {
+                        Ok(()) => require_party_character(
+                            character_id,
+                            character_manager.deref(),
+                            cmd_ctx,
+                            action,
+                        ),
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = permission {
+                        output_permission_error(e, output);
+                        return;
+                    }
+                }
+                character_sheet::character_sheet(sub_m, &character, dsa_data, output);
+            }
+        }
+        Some(("ap", sub_m)) if sub_m.value_of("action") != Some("add") => {
+            // Showing the AP doesn't change the character, so the read lock is sufficient
+            let character_manager = character_manager.read().await;
+            let result =
+                match find_edited_character(sub_m, character_manager.deref(), cmd_ctx).await {
+                    Ok(character_id) => character_manager
+                        .get_raw_character(character_id)
+                        .await
+                        .and_then(|raw| improvement::ap_command(sub_m, &raw, dsa_data, output)),
+                    Err(e) => Err(e),
+                };
+            if let Err(e) = result {
+                match e.err_type() {
+                    ErrorType::InvalidInput(_) => {
+                        output.output_line(&e);
+                    }
+                    _ => {
+                        output.output_line(&"Internal server error while reading character");
+                        println!("Error reading character: {:?}", e);
+                    }
+                }
+            }
+        }
+        Some((cmd_name, sub_m)) if ["set", "improve", "ap"].contains(&cmd_name) => {
+            if cmd_name == "ap" && sub_m.value_of("action") == Some("add") {
+                if let Err(e) = cmd_ctx
+                    .require_game_master(character_manager.read().await.deref(), "award AP")
+                    .await
+                {
+                    output_permission_error(e, output);
+                    return;
+                }
+            }
+            let edit = match cmd_name {
+                "set" => character_edit::set_command,
+                "improve" => improvement::improve_command,
+                _ => improvement::ap_command,
+            };
+            if let Err(e) =
+                edit_character(sub_m, character_manager, cmd_ctx, dsa_data, output, edit).await
+            {
+                match e.err_type() {
+                    ErrorType::InvalidInput(_) => {
+                        output.output_line(&e);
+                    }
+                    _ => {
+                        output.output_line(&"Internal server error while changing character");
+                        println!("Error changing character: {:?}", e);
+                    }
+                }
+            }
+        }
+        Some(("cost", sub_m)) => {
+            if let Err(e) = improvement::cost_command(sub_m, dsa_data, output) {
+                output.output_line(&e);
+            }
+        }
         Some(("roll", sub_m)) => {
             dsa::roll(sub_m, output);
         }
 
         Some(("ini", sub_m)) => {
-            match initiative(character_manager.read().await, &sub_m, cmd_ctx, output).await {
+            match initiative(
+                character_manager.read().await,
+                &sub_m,
+                cmd_ctx,
+                dsa_data,
+                output,
+            )
+            .await
+            {
                 Ok(()) => {}
                 Err(e) => match e.err_type() {
                     ErrorType::InvalidInput(_) => {
@@ -608,6 +928,55 @@ pub async fn execute_command<T>(
             };
         }
 
+        Some((cmd_name, sub_m)) if ["transfer", "share"].contains(&cmd_name) => {
+            let result = match cmd_name {
+                "transfer" => {
+                    transfer_command(sub_m, character_manager, cmd_ctx, config, output).await
+                }
+                _ => share_command(sub_m, character_manager, cmd_ctx, output).await,
+            };
+            if let Err(e) = result {
+                match e.err_type() {
+                    ErrorType::InvalidInput(_) => {
+                        output.output_line(&e);
+                    }
+                    _ => {
+                        output
+                            .output_line(&"Internal server error while changing character owners");
+                        println!("Error executing {} command: {:?}", cmd_name, e);
+                    }
+                }
+            }
+        }
+
+        Some(("gm", sub_m)) => {
+            if let Err(e) = game_master_command(sub_m, character_manager, cmd_ctx, output).await {
+                match e.err_type() {
+                    ErrorType::InvalidInput(_) => {
+                        output.output_line(&e);
+                    }
+                    _ => {
+                        output.output_line(&"Internal server error while managing the game master");
+                        println!("Error executing gm command: {:?}", e);
+                    }
+                }
+            }
+        }
+
+        Some(("party", sub_m)) => {
+            if let Err(e) = party_command(sub_m, character_manager, cmd_ctx, output).await {
+                match e.err_type() {
+                    ErrorType::InvalidInput(_) => {
+                        output.output_line(&e);
+                    }
+                    _ => {
+                        output.output_line(&"Internal server error while managing parties");
+                        println!("Error executing party command: {:?}", e);
+                    }
+                }
+            }
+        }
+
         Some(("rename", sub_m)) => {
             match rename(character_manager.read().await, &sub_m, cmd_ctx, output).await {
                 Ok(()) => {}
@@ -631,131 +1000,1063 @@ pub async fn execute_command<T>(
     };
 }
 
-async fn execute_character_command<O>(
-    check_fn: impl Fn(&ArgMatches, &Character, &DSAData, &Config, &mut O),
+async fn execute_character_command(
+    check_fn: impl Fn(&ArgMatches, &Character, &DSAData, &Config) -> Result<CheckResult, Error>,
     matches: &ArgMatches,
     character_manager: impl Deref<Target = CharacterManager>,
     dsa_data: &DSAData,
     config: &Config,
     ctx: &impl CommandContext,
-    output: &mut O,
-) where
-    O: OutputWrapper,
-{
-    let character_manager = character_manager.borrow();
-    let character_id = match matches.value_of("user_id") {
-        None => {
-            character_manager
-                .find_character(ctx, matches.value_of("character_name"))
-                .await
-        }
-        Some(id) => {
-            let id = match id.parse::<u64>() {
-                Ok(id) => id,
-                Err(_) => {
-                    output.output_line(&"Found invalid user id");
-                    return;
+    output: &mut impl OutputWrapper,
+) {
+    if matches.is_present("secret") {
+        if let Err(e) = secret_check(
+            check_fn,
+            matches,
+            character_manager.deref(),
+            dsa_data,
+            config,
+            ctx,
+            output,
+        )
+        .await
+        {
+            match e.err_type() {
+                ErrorType::InvalidInput(_) => {
+                    output.output_line(&e);
+                }
+                _ => {
+                    output.output_line(&"Internal server error during secret check");
+                    println!("Error during secret check: {:?}", e);
                 }
-            };
-            character_manager
-                .find_character_for_user(id, matches.value_of("character_name"))
-                .await
-        }
-    };
-    let character_id = match character_id {
-        Ok(id) => id,
-        Err(e) => match e.err_type() {
-            ErrorType::InvalidInput(_) => {
-                output.output_line(&e);
-                return;
-            }
-            _ => {
-                output.output_line(&"Internal server error while matching character");
-                println!("Error matching character: {}", e);
-                return;
-            }
-        },
-    };
-    let character = match character_manager.get_character(character_id).await {
-        Ok(c) => c,
-        Err(e) => match e.err_type() {
-            ErrorType::InvalidInput(_) => {
-                output.output_line(&e);
-                return;
             }
-            _ => {
-                output.output_line(&"Internal server error while loading character");
-                println!("Error loading character: {}", e);
-                return;
+        }
+        return;
+    }
+    if matches.is_present("all") {
+        if let Err(e) = group_check(
+            check_fn,
+            matches,
+            character_manager.deref(),
+            dsa_data,
+            config,
+            ctx,
+            output,
+        )
+        .await
+        {
+            match e.err_type() {
+                ErrorType::InvalidInput(_) => {
+                    output.output_line(&e);
+                }
+                _ => {
+                    output.output_line(&"Internal server error during group check");
+                    println!("Error during group check: {:?}", e);
+                }
             }
-        },
-    };
-    check_fn(matches, &character, dsa_data, config, output);
+        }
+        return;
+    }
+    if let Some((_, character)) =
+        get_command_character(matches, character_manager.deref(), ctx, output).await
+    {
+        match check_fn(matches, &character, dsa_data, config) {
+            Ok(result) => dsa::output_check(&result, output),
+            Err(e) => output.output_line(&e),
+        }
+    }
 }
 
-async fn initiative<T>(
-    character_manager: impl Deref<Target = CharacterManager>,
-    sub_m: &clap::ArgMatches,
-    cmd_ctx: &T,
+/*
+Rolls a check, whose result is only sent to the game master by direct message.
+With "hint", the owner of the character receives a vague description of the result
+*/
+async fn secret_check(
+    check_fn: impl Fn(&ArgMatches, &Character, &DSAData, &Config) -> Result<CheckResult, Error>,
+    matches: &ArgMatches,
+    character_manager: &CharacterManager,
+    dsa_data: &DSAData,
+    config: &Config,
+    ctx: &impl CommandContext,
     output: &mut impl OutputWrapper,
-) -> Result<(), Error>
-where
-    T: CommandContext,
-{
-    //Reset trumps all other arguments
-    if sub_m.is_present("reset") {
-        let members = cmd_ctx.members_in_channel().await?;
-        let mut rename_futs = Vec::new();
-        for member in members {
+) -> Result<(), Error> {
+    let mut gm_output = game_master_output(character_manager, ctx).await?;
+    if matches.is_present("all") {
+        if matches.is_present("hint") {
+            return Err(Error::new(
+                "Hints can only be sent for the check of a single character",
+                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+            ));
+        }
+        group_check(
+            check_fn,
+            matches,
+            character_manager,
+            dsa_data,
+            config,
+            ctx,
+            &mut gm_output,
+        )
+        .await?;
+        gm_output.send(ctx.context()).await;
+        output.output_line(&"Hidden checks were rolled for the party");
+        return Ok(());
+    }
+
+    let (character_id, character) =
+        match get_command_character(matches, character_manager, ctx, output).await {
+            Some(c) => c,
+            None => {
+                return Ok(());
+            }
+        };
+    let result = check_fn(matches, &character, dsa_data, config)?;
+    dsa::output_check(&result, &mut gm_output);
+    gm_output.send(ctx.context()).await;
+    if matches.is_present("hint") {
+        send_hint(&result, character_id, &character, character_manager, ctx).await;
+    }
+    output.output_line(&"A hidden check was rolled");
+    Ok(())
+}
+
+// Creates an output that sends everything to the game master by direct message
+async fn game_master_output(
+    character_manager: &CharacterManager,
+    ctx: &impl CommandContext,
+) -> Result<DiscordOutputWrapper<'static>, Error> {
+    Ok(DiscordOutputWrapper::new(DiscordOutputType::DirectMessage(
+        ctx.game_master_users(character_manager).await?,
+    )))
+}
+
+// Sends the owner of the character a vague description of the result of a secret check
+async fn send_hint(
+    result: &CheckResult,
+    character_id: CharacterId,
+    character: &Character,
+    character_manager: &CharacterManager,
+    ctx: &impl CommandContext,
+) {
+    if let Some(owner) = character_manager.get_character_owner(character_id) {
+        let mut player_output =
+            DiscordOutputWrapper::new(DiscordOutputType::DirectMessage(vec![UserId(owner)]));
+        player_output.output_line(&format!(
+            "{}: {}",
+            character.get_name(),
+            dsa::vague_result(result)
+        ));
+        player_output.send(ctx.context()).await;
+    }
+}
+
+/*
+Executes the "extended" command. With "secret", the status is only sent to the game master
+and the owner of the character can receive a hint about the result of a roll
+*/
+async fn extended_command(
+    matches: &ArgMatches,
+    character_manager: &CharacterManager,
+    extended_checks_lock: &Mutex<()>,
+    dsa_data: &DSAData,
+    config: &Config,
+    ctx: &impl CommandContext,
+    output: &mut impl OutputWrapper,
+) -> Result<(), Error> {
+    let (character_id, character) =
+        match get_command_character(matches, character_manager, ctx, output).await {
+            Some(c) => c,
+            None => {
+                return Ok(());
+            }
+        };
+    let character_key = format!("discord:{}", character_id);
+    // The progress of all extended checks is stored in one file, the lock keeps concurrent rolls from losing updates
+    let extended_checks = extended_checks_lock.lock().await;
+    if !matches.is_present("secret") {
+        extended_check::extended_check(
+            matches,
+            &character,
+            &character_key,
+            dsa_data,
+            config,
+            output,
+        )
+        .await?;
+        return Ok(());
+    }
+    let mut gm_output = game_master_output(character_manager, ctx).await?;
+    let result = extended_check::extended_check(
+        matches,
+        &character,
+        &character_key,
+        dsa_data,
+        config,
+        &mut gm_output,
+    )
+    .await?;
+    drop(extended_checks);
+    gm_output.send(ctx.context()).await;
+    if let (Some(result), true) = (result, matches.is_present("hint")) {
+        send_hint(&result, character_id, &character, character_manager, ctx).await;
+    }
+    output.output_line(&"The extended check was sent to the game master");
+    Ok(())
+}
+
+/*
+Rolls the check for all characters of the party in this channel and displays a summary.
+Characters that can't roll the check are listed below it with the reason
+*/
+async fn group_check(
+    check_fn: impl Fn(&ArgMatches, &Character, &DSAData, &Config) -> Result<CheckResult, Error>,
+    matches: &ArgMatches,
+    character_manager: &CharacterManager,
+    dsa_data: &DSAData,
+    config: &Config,
+    ctx: &impl CommandContext,
+    output: &mut impl OutputWrapper,
+) -> Result<(), Error> {
+    let (members, missing) = ctx.party_members(character_manager).await?;
+    output_missing_members(&missing, character_manager, output);
+    if members.is_empty() {
+        return Err(Error::new(
+            "The party in this channel has no members",
+            ErrorType::InvalidInput(InputErrorType::InvalidDiscordContext),
+        ));
+    }
+    let mut results: Vec<CheckResult> = Vec::new();
+    let mut failed_checks: Vec<(String, Error)> = Vec::new();
+    for (_, character_id) in members {
+        let character = character_manager.get_character(character_id).await?;
+        // A character that can't roll the check, e.g. because it lacks a custom technique, doesn't stop the others
+        match check_fn(matches, &character, dsa_data, config) {
+            Ok(result) => results.push(result),
+            Err(e) => failed_checks.push((character.get_name().to_string(), e)),
+        }
+    }
+    dsa::output_group_check(&mut results, output);
+    if !results.is_empty() && !failed_checks.is_empty() {
+        output.new_line();
+    }
+    for (character_name, e) in failed_checks {
+        output.output_line(&format!("{}: {}", character_name, e));
+    }
+    Ok(())
+}
+
+/*
+Offers a character to another user, or accepts or declines a character offered to the sender.
+Game masters can offer the characters of other users in their party, e.g. of players that left
+*/
+async fn transfer_command(
+    matches: &ArgMatches,
+    character_manager: &RwLock<CharacterManager>,
+    ctx: &impl CommandContext,
+    config: &Config,
+    output: &mut impl OutputWrapper,
+) -> Result<(), Error> {
+    let sender = *ctx.sender()?.as_u64();
+    let mut character_manager = character_manager.write().await;
+    match matches.value_of("action").unwrap() {
+        "offer" => {
+            let receiver = match matches.value_of("user_id").map(|id| id.parse::<u64>()) {
+                Some(Ok(id)) => id,
+                Some(Err(_)) => {
+                    return Err(Error::new(
+                        "Found invalid user id",
+                        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                    ));
+                }
+                None => {
+                    return Err(Error::new(
+                        "Offering a character requires the user that receives it",
+                        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                    ));
+                }
+            };
+            let character_id = character_manager
+                .find_character(ctx, matches.value_of("character_name"))
+                .await?;
+            if character_manager.get_character_owner(character_id) != Some(sender) {
+                let action = "transfer the characters of other users";
+                ctx.require_game_master(character_manager.deref(), action)
+                    .await?;
+                require_party_character(character_id, character_manager.deref(), ctx, action)?;
+            }
+            let (name, withdrawn) = character_manager
+                .offer_transfer(character_id, receiver)
+                .await?;
+            if withdrawn.is_some() {
+                output.output_line(&format!(
+                    "Withdrew the earlier offer of \"{}\" to another user",
+                    name
+                ));
+            }
+            output.output_line(&format!(
+                "Offered \"{}\", the receiving user has to confirm the transfer with the \"transfer accept\" command",
+                name
+            ));
+        }
+        "accept" => {
+            let name = character_manager
+                .accept_transfer(sender, matches.value_of("character_name"), config)
+                .await?;
+            output.output_line(&format!("\"{}\" now belongs to you", name));
+        }
+        _ => {
+            let name = character_manager
+                .decline_transfer(sender, matches.value_of("character_name"))
+                .await?;
+            output.output_line(&format!("Declined the transfer of \"{}\"", name));
+        }
+    }
+    Ok(())
+}
+
+/*
+Allows another user to roll for one of the senders characters, or takes this right away again
+*/
+async fn share_command(
+    matches: &ArgMatches,
+    character_manager: &RwLock<CharacterManager>,
+    ctx: &impl CommandContext,
+    output: &mut impl OutputWrapper,
+) -> Result<(), Error> {
+    let sender = *ctx.sender()?.as_u64();
+    let user_id = match matches.value_of("user_id").unwrap().parse::<u64>() {
+        Ok(id) => id,
+        Err(_) => {
+            return Err(Error::new(
+                "Found invalid user id",
+                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+            ));
+        }
+    };
+    let mut character_manager = character_manager.write().await;
+    let character_id = character_manager
+        .find_character_for_user(
+            sender,
+            ctx.guild().map(|g| *g.as_u64()),
+            matches.value_of("character_name"),
+        )
+        .await?;
+    let shared = !matches.is_present("remove");
+    let name = character_manager
+        .share_character(sender, character_id, user_id, shared)
+        .await?;
+    if shared {
+        output.output_line(&format!(
+            "The user can now roll for \"{}\" with the \"character\" option",
+            name
+        ));
+    } else {
+        output.output_line(&format!("\"{}\" is no longer shared with the user", name));
+    }
+    Ok(())
+}
+
+/*
+Shows, sets or removes the game master of the guild the command was sent in.
+Only the guild owner can change the game master
+*/
+async fn game_master_command(
+    matches: &ArgMatches,
+    character_manager: &RwLock<CharacterManager>,
+    ctx: &impl CommandContext,
+    output: &mut impl OutputWrapper,
+) -> Result<(), Error> {
+    let guild_id = match ctx.guild() {
+        Some(guild_id) => *guild_id.as_u64(),
+        None => {
+            return Err(Error::new(
+                "Game masters can only be set in server channels",
+                ErrorType::InvalidInput(InputErrorType::InvalidDiscordContext),
+            ));
+        }
+    };
+    let action = matches.value_of("action").unwrap();
+    if action == "show" {
+        match character_manager.read().await.get_game_master(guild_id) {
+            Some(game_master) => output.output_line(&format!(
+                "The game master of this server is {}",
+                game_master.describe()
+            )),
+            None => output.output_line(
+                &"No game master is set, only the server owner has the rights of a game master",
+            ),
+        }
+        return Ok(());
+    }
+
+    if ctx.get_guild_owner().await? != Some(ctx.sender()?) {
+        return Err(Error::new(
+            "Only the server owner may change the game master",
+            ErrorType::InvalidInput(InputErrorType::MissingPermission),
+        ));
+    }
+    let game_master = match action {
+        "set" => {
+            let parse_id = |id: &str| match id.parse::<u64>() {
+                Ok(id) => Ok(id),
+                Err(_) => Err(Error::new(
+                    format!("Invalid id \"{}\"", id),
+                    ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                )),
+            };
+            match (matches.value_of("user_id"), matches.value_of("role_id")) {
+                (Some(user_id), None) => Some(GameMaster::User(parse_id(user_id)?)),
+                (None, Some(role_id)) => Some(GameMaster::Role(parse_id(role_id)?)),
+                _ => {
+                    return Err(Error::new(
+                        "Setting the game master requires either a user or a role",
+                        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                    ));
+                }
+            }
+        }
+        _ => None,
+    };
+    character_manager
+        .write()
+        .await
+        .set_game_master(guild_id, game_master)
+        .await?;
+    match game_master {
+        Some(game_master) => output.output_line(&format!(
+            "The game master of this server is now {}",
+            game_master.describe()
+        )),
+        None => output.output_line(&"Removed the game master of this server"),
+    }
+    Ok(())
+}
+
+/*
+Creates, joins, leaves or lists the parties of the guild the command was sent in
+*/
+async fn party_command(
+    matches: &ArgMatches,
+    character_manager: &RwLock<CharacterManager>,
+    ctx: &impl CommandContext,
+    output: &mut impl OutputWrapper,
+) -> Result<(), Error> {
+    let guild_id = match ctx.guild() {
+        Some(guild_id) => *guild_id.as_u64(),
+        None => {
+            return Err(Error::new(
+                "Parties can only be used in server channels",
+                ErrorType::InvalidInput(InputErrorType::InvalidDiscordContext),
+            ));
+        }
+    };
+    let sender = *ctx.sender()?.as_u64();
+    let action = matches.value_of("action").unwrap();
+    if action == "list" {
+        let character_manager = character_manager.read().await;
+        let mut num_parties = 0;
+        for party in character_manager.parties(guild_id) {
+            let names: Vec<&str> = party
+                .members
+                .iter()
+                .filter_map(|m| {
+                    character_manager
+                        .get_character_name(m.user_id, m.character_id)
+                        .ok()
+                })
+                .collect();
+            output.output_line(&format!(
+                "{} ({}): {}",
+                party.name,
+                party.location(),
+                if names.is_empty() {
+                    String::from("No members")
+                } else {
+                    names.join(", ")
+                }
+            ));
+            num_parties += 1;
+        }
+        if num_parties == 0 {
+            output.output_line(&"There are no parties on this server");
+        }
+        return Ok(());
+    }
+
+    let party_name = match matches.value_of("party_name") {
+        Some(name) => name,
+        None => {
+            return Err(Error::new(
+                format!("The action \"{}\" requires a party name", action),
+                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+            ));
+        }
+    };
+    let mut character_manager = character_manager.write().await;
+    match action {
+        "create" => {
+            let channel_id = if matches.is_present("guild") {
+                None
+            } else {
+                Some(*ctx.channel()?.as_u64())
+            };
+            let name = character_manager
+                .create_party(sender, guild_id, channel_id, party_name)
+                .await?;
+            output.output_line(&format!(
+                "Created the party \"{}\", use the \"party join\" command to add your character",
+                name
+            ));
+        }
+        "join" => {
+            let character_id = character_manager
+                .find_character_for_user(sender, Some(guild_id), matches.value_of("character_name"))
+                .await?;
+            let name = character_manager
+                .join_party(sender, guild_id, party_name, character_id)
+                .await?;
+            output.output_line(&format!(
+                "\"{}\" joined the party \"{}\"",
+                character_manager.get_character_name(sender, character_id)?,
+                name
+            ));
+        }
+        "disband" => {
+            let is_game_master = ctx.is_game_master(character_manager.deref()).await?;
+            let name = character_manager
+                .disband_party(sender, is_game_master, guild_id, party_name)
+                .await?;
+            output.output_line(&format!("Disbanded the party \"{}\"", name));
+        }
+        _ => {
+            let name = character_manager
+                .leave_party(sender, guild_id, party_name)
+                .await?;
+            output.output_line(&format!("You left the party \"{}\"", name));
+        }
+    }
+    Ok(())
+}
+
+/*
+Executes the "history", "diff" and "rollback" commands for one of the senders characters
+*/
+async fn character_history_command(
+    cmd_name: &str,
+    matches: &ArgMatches,
+    character_manager: &RwLock<CharacterManager>,
+    ctx: &impl CommandContext,
+    output: &mut impl OutputWrapper,
+) -> Result<(), Error> {
+    let sender = *ctx.sender()?.as_u64();
+    let mut character_manager = character_manager.write().await;
+    let character_id = character_manager
+        .find_character_for_user(
+            sender,
+            ctx.guild().map(|g| *g.as_u64()),
+            matches.value_of("character_name"),
+        )
+        .await?;
+    let name = character_manager
+        .get_character_name(sender, character_id)?
+        .to_string();
+    let parse_version = |arg: &str| -> Result<u64, Error> {
+        matches.value_of(arg).unwrap().parse().map_err(|_| {
+            Error::new(
+                format!("Unable to parse {}: Argument must be a version number", arg),
+                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+            )
+        })
+    };
+    match cmd_name {
+        "history" => {
+            let versions = character_manager.get_versions(sender, character_id)?;
+            if versions.is_empty() {
+                output.output_line(&format!("No versions recorded for \"{}\"", name));
+                return Ok(());
+            }
+            output.output_line(&format!("Versions of \"{}\":", name));
+            let mut table = vec![vec![
+                String::from("Version"),
+                String::from("Saved"),
+                String::from("Note"),
+            ]];
+            for (i, version) in versions.iter().enumerate() {
+                let mut notes = Vec::new();
+                if let Some(restored_from) = version.restored_from {
+                    notes.push(format!("Restored from version {}", restored_from));
+                }
+                if i + 1 == versions.len() {
+                    notes.push(String::from("Current"));
+                }
+                table.push(vec![
+                    version.number.to_string(),
+                    format_timestamp(version.time),
+                    notes.join(", "),
+                ]);
+            }
+            output.output_table(&table);
+        }
+        "diff" => {
+            let from = parse_version("from")?;
+            let old = character_manager
+                .get_character_version(sender, character_id, from)
+                .await?;
+            let (new, to_name) = match matches.value_of("to") {
+                Some(_) => {
+                    let to = parse_version("to")?;
+                    (
+                        Arc::new(
+                            character_manager
+                                .get_character_version(sender, character_id, to)
+                                .await?,
+                        ),
+                        format!("version {}", to),
+                    )
+                }
+                None => (
+                    character_manager.get_character(character_id).await?,
+                    String::from("the current version"),
+                ),
+            };
+            let table = character_history::diff_characters(&old, &new);
+            if table.len() == 1 {
+                output.output_line(&format!(
+                    "No differences between version {} and {}",
+                    from, to_name
+                ));
+            } else {
+                output.output_line(&format!(
+                    "Changes of \"{}\" from version {} to {}:",
+                    name, from, to_name
+                ));
+                output.output_table(&table);
+            }
+        }
+        "rollback" => {
+            let version = parse_version("version")?;
+            let new_version = character_manager
+                .rollback_character(sender, character_id, version)
+                .await?;
+            output.output_line(&format!(
+                "Restored version {} of \"{}\" as version {}",
+                version, name, new_version
+            ));
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/*
+Starts the character creation wizard for the sender. The wizard is continued in a direct message channel
+*/
+async fn start_character_creation(
+    matches: &ArgMatches,
+    creation_wizards: &Mutex<HashMap<u64, (CharacterWizard, Instant)>>,
+    ctx: &impl CommandContext,
+    config: &Config,
+    dsa_data: &DSAData,
+    output: &mut impl OutputWrapper,
+) -> Result<(), Error> {
+    let wizard = CharacterWizard::new(matches, Some(config.discord.max_name_length))?;
+    let sender = ctx.sender()?;
+    let dm_channel = sender.create_dm_channel(ctx.context()).await?;
+    dm_channel
+        .say(
+            ctx.context(),
+            format!(
+                "Creating a new character, answer \"cancel\" to abort\n{}",
+                wizard.prompt(dsa_data)
+            ),
+        )
+        .await?;
+    let mut creation_wizards = creation_wizards.lock().await;
+    remove_expired_wizards(&mut creation_wizards);
+    creation_wizards.insert(*sender.as_u64(), (wizard, Instant::now()));
+    output.output_line(&"Sent you a direct message to create your character");
+    Ok(())
+}
+
+/*
+Passes a direct message to the character creation wizard of its author, if there is one.
+Once the wizard is done, the new character is stored like an uploaded character
+*/
+pub async fn continue_character_creation(
+    message: &Message,
+    creation_wizards: &Mutex<HashMap<u64, (CharacterWizard, Instant)>>,
+    character_manager: &RwLock<CharacterManager>,
+    config: &Config,
+    dsa_data: &DSAData,
+    output: &mut impl OutputWrapper,
+) {
+    let user_id = *message.author.id.as_u64();
+    // The wizards are not locked while the character is added, so other users can continue meanwhile
+    let raw = {
+        let mut creation_wizards = creation_wizards.lock().await;
+        if remove_expired_wizards(&mut creation_wizards).contains(&user_id) {
+            output.output_line(
+                &"Character creation timed out, use the \"create\" command to start again",
+            );
+            return;
+        }
+        let (wizard, last_input) = match creation_wizards.get_mut(&user_id) {
+            Some(w) => w,
+            None => {
+                return;
+            }
+        };
+        *last_input = Instant::now();
+        if message.content.trim() == "cancel" {
+            creation_wizards.remove(&user_id);
+            output.output_line(&"Character creation cancelled");
+            return;
+        }
+        if let Err(e) = wizard.handle_input(&message.content, dsa_data) {
+            output.output_line(&e);
+        }
+        output.output_line(&wizard.prompt(dsa_data));
+        if !wizard.is_done() {
+            return;
+        }
+        match wizard.to_raw(dsa_data) {
+            Ok(raw) => raw,
+            Err(e) => {
+                creation_wizards.remove(&user_id);
+                output.output_line(&"Internal server error");
+                println!("Error converting created character: {}", e);
+                return;
+            }
+        }
+    };
+    let result = character_manager
+        .write()
+        .await
+        .add_character(user_id, None, raw, config)
+        .await;
+    // The answers are kept if the character can't be added, e.g. because the user has too many characters
+    if result.is_ok() {
+        creation_wizards.lock().await.remove(&user_id);
+    }
+    let failed = result.is_err();
+    output_added_character(result, "created", output);
+    if failed {
+        output.output_line(&"Send another message to try again or \"cancel\" to abort");
+    }
+}
+
+// Removes all character creation wizards that timed out and returns the ids of their users
+fn remove_expired_wizards(
+    creation_wizards: &mut HashMap<u64, (CharacterWizard, Instant)>,
+) -> Vec<u64> {
+    let expired: Vec<u64> = creation_wizards
+        .iter()
+        .filter(|(_, (_, last_input))| last_input.elapsed() > CREATION_WIZARD_TIMEOUT)
+        .map(|(user_id, _)| *user_id)
+        .collect();
+    for user_id in &expired {
+        creation_wizards.remove(user_id);
+    }
+    expired
+}
+
+// Reports the party characters that were skipped, because their players could not be found in the guild
+fn output_missing_members(
+    missing: &[CharacterId],
+    character_manager: &CharacterManager,
+    output: &mut impl OutputWrapper,
+) {
+    for character_id in missing {
+        let name = character_manager
+            .get_character_owner(*character_id)
+            .and_then(|user_id| {
+                character_manager
+                    .get_character_name(user_id, *character_id)
+                    .ok()
+            })
+            .unwrap_or("Unknown character");
+        output.output_line(&format!(
+            "Skipped \"{}\", its player could not be found on this server",
+            name
+        ));
+    }
+}
+
+// Reports the result of adding an uploaded or created character
+fn output_added_character(
+    result: Result<(bool, String), Error>,
+    action: &str,
+    output: &mut impl OutputWrapper,
+) {
+    match result {
+        Ok((true, name)) => {
+            output.output_line(&format!("Successfully replaced character \"{}\"", name));
+        }
+        Ok((false, name)) => {
+            output.output_line(&format!("Successfully {} character \"{}\"", action, name));
+        }
+        Err(e) => match e.err_type() {
+            ErrorType::InvalidInput(_) => {
+                output.output_line(&e);
+            }
+            _ => {
+                output.output_line(&"Internal server error");
+                println!("Error adding character: {}", e);
+            }
+        },
+    };
+}
+
+/*
+Changes one of the senders characters by applying the given edit function to its data.
+Game masters can select the character of another user with the "user_id" argument
+*/
+async fn edit_character<O: OutputWrapper>(
+    matches: &ArgMatches,
+    character_manager: &RwLock<CharacterManager>,
+    ctx: &impl CommandContext,
+    dsa_data: &DSAData,
+    output: &mut O,
+    edit: fn(&ArgMatches, &[u8], &DSAData, &mut O) -> Result<Vec<u8>, Error>,
+) -> Result<(), Error> {
+    let mut character_manager = character_manager.write().await;
+    let character_id = find_edited_character(matches, character_manager.deref(), ctx).await?;
+    let raw = character_manager.get_raw_character(character_id).await?;
+    let raw = edit(matches, &raw, dsa_data, output)?;
+    character_manager
+        .update_raw_character(character_id, raw)
+        .await?;
+    Ok(())
+}
+
+/*
+Finds the character changed by a command: One of the senders characters or, for game masters,
+a character of the user given by the "user_id" argument
+*/
+async fn find_edited_character(
+    matches: &ArgMatches,
+    character_manager: &CharacterManager,
+    ctx: &impl CommandContext,
+) -> Result<CharacterId, Error> {
+    let sender = *ctx.sender()?.as_u64();
+    let user_id = match matches.value_of("user_id").map(|id| id.parse::<u64>()) {
+        None => sender,
+        Some(Ok(id)) => id,
+        Some(Err(_)) => {
+            return Err(Error::new(
+                "Found invalid user id",
+                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+            ));
+        }
+    };
+    let action = "change the characters of other users";
+    if user_id != sender {
+        ctx.require_game_master(character_manager, action).await?;
+    }
+    let character_id = character_manager
+        .find_character_for_user(
+            user_id,
+            ctx.guild().map(|g| *g.as_u64()),
+            matches.value_of("character_name"),
+        )
+        .await?;
+    if user_id != sender {
+        require_party_character(character_id, character_manager, ctx, action)?;
+    }
+    Ok(character_id)
+}
+
+/*
+Returns an error if the character doesn't play in the party of this channel.
+The rights of game masters are limited to the characters of their party
+*/
+fn require_party_character(
+    character_id: CharacterId,
+    character_manager: &CharacterManager,
+    ctx: &impl CommandContext,
+    action: &str,
+) -> Result<(), Error> {
+    let channel_id = *ctx.channel()?.as_u64();
+    let in_party = ctx
+        .guild()
+        .and_then(|guild_id| character_manager.get_party(*guild_id.as_u64(), channel_id))
+        .map_or(false, |party| {
+            party.members.iter().any(|m| m.character_id == character_id)
+        });
+    if in_party {
+        Ok(())
+    } else {
+        Err(Error::new(
+            format!(
+                "Game masters may only {} for characters in the party of this channel",
+                action
+            ),
+            ErrorType::InvalidInput(InputErrorType::MissingPermission),
+        ))
+    }
+}
+
+// Writes a failed permission check to the output, other errors are reported as internal errors
+fn output_permission_error(e: Error, output: &mut impl OutputWrapper) {
+    match e.err_type() {
+        ErrorType::InvalidInput(_) => {
+            output.output_line(&e);
+        }
+        _ => {
+            output.output_line(&"Internal server error while checking permissions");
+            println!("Error checking permissions: {:?}", e);
+        }
+    }
+}
+
+/*
+Finds and loads the character targeted by a command, using the "character_name" and "user_id" arguments.
+If no character could be loaded, an error message is written to the output
+*/
+async fn get_command_character(
+    matches: &ArgMatches,
+    character_manager: &CharacterManager,
+    ctx: &impl CommandContext,
+    output: &mut impl OutputWrapper,
+) -> Option<(CharacterId, Arc<Character>)> {
+    let character_id = match matches.value_of("user_id") {
+        None => {
+            character_manager
+                .find_character(ctx, matches.value_of("character_name"))
+                .await
+        }
+        Some(id) => {
+            let id = match id.parse::<u64>() {
+                Ok(id) => id,
+                Err(_) => {
+                    output.output_line(&"Found invalid user id");
+                    return None;
+                }
+            };
+            let is_sender = ctx.sender().map_or(false, |s| *s.as_u64() == id);
+            if !is_sender {
+                if let Err(e) = ctx
+                    .require_game_master(character_manager, "use the characters of other users")
+                    .await
+                {
+                    output_permission_error(e, output);
+                    return None;
+                }
+            }
+            let character_id = character_manager
+                .find_character_for_user(
+                    id,
+                    ctx.guild().map(|g| *g.as_u64()),
+                    matches.value_of("character_name"),
+                )
+                .await;
+            match character_id {
+                Ok(character_id) if !is_sender => {
+                    if let Err(e) = require_party_character(
+                        character_id,
+                        character_manager,
+                        ctx,
+                        "use the characters of other users",
+                    ) {
+                        output_permission_error(e, output);
+                        return None;
+                    }
+                    Ok(character_id)
+                }
+                result => result,
+            }
+        }
+    };
+    let character_id = match character_id {
+        Ok(id) => id,
+        Err(e) => match e.err_type() {
+            ErrorType::InvalidInput(_) => {
+                output.output_line(&e);
+                return None;
+            }
+            _ => {
+                output.output_line(&"Internal server error while matching character");
+                println!("Error matching character: {}", e);
+                return None;
+            }
+        },
+    };
+    match character_manager.get_character(character_id).await {
+        Ok(c) => Some((character_id, c)),
+        Err(e) => match e.err_type() {
+            ErrorType::InvalidInput(_) => {
+                output.output_line(&e);
+                None
+            }
+            _ => {
+                output.output_line(&"Internal server error while loading character");
+                println!("Error loading character: {}", e);
+                None
+            }
+        },
+    }
+}
+
+async fn initiative<T>(
+    character_manager: impl Deref<Target = CharacterManager>,
+    sub_m: &clap::ArgMatches,
+    cmd_ctx: &T,
+    dsa_data: &DSAData,
+    output: &mut impl OutputWrapper,
+) -> Result<(), Error>
+where
+    T: CommandContext,
+{
+    //Reset trumps all other arguments
+    if sub_m.is_present("reset") {
+        let (members, missing) = cmd_ctx.party_members(character_manager.deref()).await?;
+        output_missing_members(&missing, character_manager.deref(), output);
+        let mut rename_futs = Vec::new();
+        for (member, character_id) in members {
             let user_id = *member.user.id.as_u64();
             /*
             Reset the nickname if all of the following apply
-            1. The user has uploaded a character
+            1. The user plays a character in the party
             2. The user has a discord nickname
             3. The discord nickname is of the form "[i64](,[i64]...,[i64]) orig_name"
             */
-            if let Ok(character_id) = character_manager
-                .find_character_for_user(user_id, None::<String>)
-                .await
-            {
-                if let Some(nickname) = member.nick.clone() {
-                    let mut new_name = String::default();
-                    if nickname.contains('Ξ') {
-                        // cool name
-                        match character_manager.get_character_name(user_id, character_id) {
-                            Err(_) => {
-                                return Err(Error::new(
-                                    format!(
-                                        "Unable to retrieve character for {}",
-                                        member.display_name()
-                                    ),
-                                    ErrorType::InvalidInput(InputErrorType::InvalidFormat),
-                                ));
-                            }
-                            Ok(character_name) => {
-                                let display_name = member.display_name();
-                                let display_name = display_name.split(" Ξ ").last().unwrap();
-                                new_name = calculate_name(&character_name, &display_name, 32)?;
-                            }
-                        };
-                    } else if let Some(index) = nickname.find(' ') {
-                        if !nickname[..index]
-                            .split(',')
-                            .all(|ini_part| ini_part.parse::<i64>().is_ok())
-                        {
-                            continue;
+            if let Some(nickname) = member.nick.clone() {
+                let mut new_name = String::default();
+                if nickname.contains('Ξ') {
+                    // cool name
+                    match character_manager.get_character_name(user_id, character_id) {
+                        Err(_) => {
+                            return Err(Error::new(
+                                format!(
+                                    "Unable to retrieve character for {}",
+                                    member.display_name()
+                                ),
+                                ErrorType::InvalidInput(InputErrorType::InvalidFormat),
+                            ));
                         }
-                        new_name = nickname[index + 1..].to_string();
-                    }
-                    rename_futs.push(async {
-                        //Force moves
-                        let member = member;
-                        let new_name = new_name;
-                        if let Err(e) = cmd_ctx.rename_member(&member, &new_name).await {
-                            println!("Error changing user nickname: {:?}", e);
+                        Ok(character_name) => {
+                            let display_name = member.display_name();
+                            let display_name = display_name.split(" Ξ ").last().unwrap();
+                            new_name = calculate_name(&character_name, &display_name, 32)?;
                         }
-                    });
+                    };
+                } else if let Some(index) = nickname.find(' ') {
+                    if !nickname[..index]
+                        .split(',')
+                        .all(|ini_part| ini_part.parse::<i64>().is_ok())
+                    {
+                        continue;
+                    }
+                    new_name = nickname[index + 1..].to_string();
                 }
+                rename_futs.push(async {
+                    //Force moves
+                    let member = member;
+                    let new_name = new_name;
+                    if let Err(e) = cmd_ctx.rename_member(&member, &new_name).await {
+                        println!("Error changing user nickname: {:?}", e);
+                    }
+                });
             }
         }
         futures::future::join_all(rename_futs).await;
@@ -769,28 +2070,23 @@ where
     let mut characters_members: Vec<Option<Member>> = Vec::new();
 
     if sub_m.is_present("all") {
-        let members = cmd_ctx.members_in_channel().await?;
+        let (members, missing) = cmd_ctx.party_members(character_manager.deref()).await?;
+        output_missing_members(&missing, character_manager.deref(), output);
 
-        for member in members {
-            let user_id = *member.user.id.as_u64();
-            if let Ok(character_id) = character_manager
-                .find_character_for_user(user_id, None::<String>)
-                .await
-            {
-                match character_manager.get_character(character_id).await {
-                    Err(_) => {
-                        return Err(Error::new(
-                            format!("Unable to retrieve character for {}", member.display_name()),
-                            ErrorType::InvalidInput(InputErrorType::InvalidFormat),
-                        ));
-                    }
-                    Ok(character) => {
-                        characters.push((
-                            character.get_name().to_string(),
-                            character.get_initiative_level(),
-                        ));
-                        characters_members.push(Some(member.clone()));
-                    }
+        for (member, character_id) in members {
+            match character_manager.get_character(character_id).await {
+                Err(_) => {
+                    return Err(Error::new(
+                        format!("Unable to retrieve character for {}", member.display_name()),
+                        ErrorType::InvalidInput(InputErrorType::InvalidFormat),
+                    ));
+                }
+                Ok(character) => {
+                    characters.push((
+                        character.get_name().to_string(),
+                        character.get_initiative_level(dsa_data),
+                    ));
+                    characters_members.push(Some(member.clone()));
                 }
             }
         }
@@ -803,7 +2099,7 @@ where
             let character = character_manager.get_character(character_id).await?;
             characters.push((
                 character.get_name().to_string(),
-                character.get_initiative_level(),
+                character.get_initiative_level(dsa_data),
             ));
             characters_members.push(None);
         } else {
@@ -902,32 +2198,27 @@ where
     T: CommandContext + Sync,
 {
     if sub_m.is_present("reset") {
-        let members = cmd_ctx.members_in_channel().await?;
+        let (members, missing) = cmd_ctx.party_members(character_manager.deref()).await?;
+        output_missing_members(&missing, character_manager.deref(), output);
         let mut rename_futs = Vec::new();
-        for member in members {
-            let user_id = *member.user.id.as_u64();
+        for (member, _) in members {
             /*
             Reset the nickname if all of the following apply
-            1. The user has uploaded a character
+            1. The user plays a character in the party
             2. The user has a discord nickname
             3. The discord nickname is of the form ".* Ξ orig_name"
             */
-            if let Ok(_) = character_manager
-                .find_character_for_user(user_id, None::<String>)
-                .await
-            {
-                if let Some(nickname) = member.nick.clone() {
-                    if let Some(index) = nickname.find('Ξ') {
-                        let new_name = nickname[index + 2..].to_string();
-                        rename_futs.push(async {
-                            //Force moves
-                            let member = member;
-                            let new_name = new_name;
-                            if let Err(e) = cmd_ctx.rename_member(&member, &new_name).await {
-                                println!("Error changing user nickname: {:?}", e);
-                            }
-                        });
-                    }
+            if let Some(nickname) = member.nick.clone() {
+                if let Some(index) = nickname.find('Ξ') {
+                    let new_name = nickname[index + 2..].to_string();
+                    rename_futs.push(async {
+                        //Force moves
+                        let member = member;
+                        let new_name = new_name;
+                        if let Err(e) = cmd_ctx.rename_member(&member, &new_name).await {
+                            println!("Error changing user nickname: {:?}", e);
+                        }
+                    });
                 }
             }
         }
@@ -936,9 +2227,10 @@ where
         return Ok(());
     }
 
-    let members = cmd_ctx.members_in_channel().await?;
+    let (members, missing) = cmd_ctx.party_members(character_manager.deref()).await?;
+    output_missing_members(&missing, character_manager.deref(), output);
     let mut rename_futs = Vec::new();
-    for member in members {
+    for (member, character_id) in members {
         let user_id = *member.user.id.as_u64();
 
         let mut nickname = member.user.name.clone();
@@ -955,58 +2247,53 @@ where
             println!("{}", out);
             return Ok(());
         }
-        if let Ok(character_id) = character_manager
-            .find_character_for_user(user_id, None::<String>)
-            .await
-        {
-            match character_manager.get_character_name(user_id, character_id) {
-                Err(_) => {
-                    return Err(Error::new(
-                        format!("Unable to retrieve character for {}", member.display_name()),
-                        ErrorType::InvalidInput(InputErrorType::InvalidFormat),
-                    ));
-                }
-                Ok(character_name) => {
-                    let new_name = calculate_name(&character_name, &nickname, 32)?;
+        match character_manager.get_character_name(user_id, character_id) {
+            Err(_) => {
+                return Err(Error::new(
+                    format!("Unable to retrieve character for {}", member.display_name()),
+                    ErrorType::InvalidInput(InputErrorType::InvalidFormat),
+                ));
+            }
+            Ok(character_name) => {
+                let new_name = calculate_name(&character_name, &nickname, 32)?;
 
-                    rename_futs.push(async {
-                        let member = member;
-                        let new_name = new_name;
-                        if let Err(e) = cmd_ctx.rename_member(&member, &new_name).await {
-                            if e.message() == "Missing Permissions" {
-                                match &cmd_ctx.get_guild_owner().await {
-                                    Ok(Some(owner)) => {
-                                        if owner == &member.user.id {
-                                            return Ok(Some(format!(
-                                                "Unable to change server owners nickname to {}",
-                                                new_name
-                                            )));
-                                        }
+                rename_futs.push(async {
+                    let member = member;
+                    let new_name = new_name;
+                    if let Err(e) = cmd_ctx.rename_member(&member, &new_name).await {
+                        if e.message() == "Missing Permissions" {
+                            match &cmd_ctx.get_guild_owner().await {
+                                Ok(Some(owner)) => {
+                                    if owner == &member.user.id {
+                                        return Ok(Some(format!(
+                                            "Unable to change server owners nickname to {}",
+                                            new_name
+                                        )));
                                     }
-                                    _ => {}
                                 }
+                                _ => {}
                             }
+                        }
 
-                            println!(
-                                "Error changing user nickname from {} to {}: {:?}",
+                        println!(
+                            "Error changing user nickname from {} to {}: {:?}",
+                            member.display_name(),
+                            new_name,
+                            e
+                        );
+                        return Err(Error::new(
+                            format!(
+                                "Unable to change nickname for {}: {}",
                                 member.display_name(),
-                                new_name,
-                                e
-                            );
-                            return Err(Error::new(
-                                format!(
-                                    "Unable to change nickname for {}: {}",
-                                    member.display_name(),
-                                    e.message()
-                                ),
-                                ErrorType::IO(IOErrorType::Discord),
-                            ));
-                        }
-                        Ok(None)
-                    });
-                }
-            };
-        }
+                                e.message()
+                            ),
+                            ErrorType::IO(IOErrorType::Discord),
+                        ));
+                    }
+                    Ok(None)
+                });
+            }
+        };
     }
     let res = futures::future::join_all(rename_futs).await;
     let iter = res.iter();
@@ -1,7 +1,7 @@
 use std::cmp::max;
 
 use super::character::Character;
-use super::config::{self, Config, DSAData};
+use super::config::{self, CombatManeuverConfig, Config, DSAData, RerollType};
 use super::util::*;
 use clap::ArgMatches;
 use itertools::Itertools;
@@ -12,6 +12,18 @@ use rand::Rng;
 const MAX_NUM_DICE: u32 = 100;
 //The maximum number of expressions in a roll command
 const MAX_ROLL_EXPRESSIONS: u32 = 20;
+//The minimum level for all attributes of a talent to allow a routine check
+const ROUTINE_MIN_ATTRIBUTE: i64 = 13;
+//The bonus to the skill level when using a specialization of a talent
+const SPECIALIZATION_BONUS: i64 = 2;
+//The penalty for a ranged attack against a moving target
+const MOVING_TARGET_PENALTY: i64 = -2;
+//The penalty for a ranged attack per level of bad visibility
+const VISIBILITY_PENALTY: i64 = 2;
+const MAX_VISIBILITY_LEVEL: i64 = 3;
+//The bonus for a ranged attack per action spent aiming
+const AIM_BONUS: i64 = 2;
+const MAX_AIM_ACTIONS: i64 = 2;
 
 enum CheckType {
     //A simple check where you have to roll below your attributes (for example an attribute check)
@@ -30,54 +42,84 @@ enum CritType {
 }
 
 // The facilitation for a skill check
-struct Facilitation {
+pub struct Facilitation {
+    // The facilitation that was given for the whole check
+    flat_facilitation: i64,
     // The facilitation for the individual attributes
     individual_facilitation: Vec<i64>,
     // The bonus to the available points/level, only applies for a PointsCheck
     points_bonus: i64,
 }
+
+impl Facilitation {
+    // Adds a modifier to the whole check
+    fn add(&mut self, amount: i64) {
+        self.flat_facilitation += amount;
+        for f in self.individual_facilitation.iter_mut() {
+            *f += amount;
+        }
+    }
+}
+
 // enum Facilitation {
 //     SimpleFacilitation(i64),
 //     IndividualFacilitation(Vec<i64>),
 // }
 
+// The outcome of a check, as computed by roll_check. Use output_check to display it
+pub struct CheckResult {
+    check_name: String,
+    character_name: String,
+    // The (short) names and levels of the attributes that were rolled against
+    attributes: Vec<(String, i64)>,
+    facilitation: Facilitation,
+    check_type: CheckType,
+    crit_type: CritType,
+    // The rolls that count for the result (after any rerolls)
+    pub rolls: Vec<i64>,
+    // The (original, new) rolls for all dice that were rerolled
+    rerolls: Vec<Option<(i64, i64)>>,
+    // The confirmation rolls for ConfirmableCrits
+    crit_rolls: Vec<Option<i64>>,
+    pub passed: bool,
+    // The quality level of a passed PointsCheck (0 for failed or simple checks)
+    pub quality: u32,
+    pub crit_successes: u32,
+    pub unconfirmed_crit_successes: u32,
+    pub crit_failures: u32,
+    pub unconfirmed_crit_failures: u32,
+    // True for a routine check, where no dice were rolled
+    routine: bool,
+    // Additional information that is displayed together with the result
+    notes: Vec<String>,
+    // Additional information that is only displayed if the check passed
+    pass_notes: Vec<String>,
+}
+
 pub fn attribute_check(
     cmd_matches: &ArgMatches,
     character: &Character,
     dsa_data: &DSAData,
     _: &Config,
-    output: &mut impl OutputWrapper,
-) {
-    let (attr_name, attr_info) = match DSAData::match_search(
+) -> Result<CheckResult, Error> {
+    let (attr_name, attr_info) = DSAData::match_search(
         dsa_data.attributes.iter(),
         cmd_matches.value_of("attribute_name").unwrap(),
-    ) {
-        Ok(name) => name,
-        Err(e) => {
-            output.output_line(&e);
-            return;
-        }
-    };
-    let facilitation = match get_facilitation(cmd_matches, &[attr_name]) {
-        Ok(f) => f,
-        Err(e) => {
-            output.output_line(&e);
-            return;
-        }
-    };
+    )?;
+    let facilitation = get_facilitation(cmd_matches, &[attr_name])?;
     let attr = vec![(
         attr_info.short_name.as_str(),
         character.get_attribute_level(attr_name),
     )];
-    roll_check(
+    Ok(roll_check(
         &attr,
         attr_name,
         character.get_name(),
         facilitation,
         CheckType::SimpleCheck,
         CritType::ConfirmableCrits,
-        output,
-    );
+        &[],
+    ))
 }
 
 pub fn talent_check(
@@ -85,27 +127,152 @@ pub fn talent_check(
     character: &Character,
     dsa_data: &DSAData,
     config: &Config,
-    output: &mut impl OutputWrapper,
-) {
-    let (talent_name, talent_entry) = match DSAData::match_search(
-        dsa_data.talents.iter(),
+) -> Result<CheckResult, Error> {
+    let (talent_name, talent_attrs) = DSAData::match_search(
+        dsa_data
+            .talents
+            .iter()
+            .map(|(k, v)| (k, &v.attributes))
+            .chain(character.get_custom_talents()),
         cmd_matches.value_of("skill_name").unwrap(),
-    ) {
-        Ok(name) => name,
-        Err(e) => {
-            output.output_line(&e);
-            return;
+    )?;
+    let mut facilitation = get_facilitation(cmd_matches, talent_attrs)?;
+
+    let mut notes: Vec<String> = Vec::new();
+    if let Some(area) = cmd_matches.value_of("specialization") {
+        let specializations = character.get_skill_specializations(talent_name);
+        match DSAData::match_search(specializations.iter().map(|s| (s, ())), area) {
+            Ok((specialization, _)) => {
+                facilitation.points_bonus += SPECIALIZATION_BONUS;
+                notes.push(format!(
+                    "Using specialization {} (+{})",
+                    specialization, SPECIALIZATION_BONUS
+                ));
+            }
+            Err(e) => {
+                if specializations.is_empty() {
+                    return Err(Error::new(
+                        format!(
+                            "{} has no specializations for {}",
+                            character.get_name(),
+                            uppercase_first(talent_name)
+                        ),
+                        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                    ));
+                }
+                return Err(Error::new(
+                    format!(
+                        "{} (available specializations: {})",
+                        e,
+                        specializations.join(", ")
+                    ),
+                    ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                ));
+            }
         }
-    };
-    let skill_attrs = &talent_entry.attributes;
-    let facilitation = match get_facilitation(cmd_matches, skill_attrs) {
-        Ok(f) => f,
-        Err(e) => {
-            output.output_line(&e);
-            return;
+    }
+    if cmd_matches.is_present("routine") {
+        match routine_check_quality(talent_name, talent_attrs, character, &facilitation) {
+            Ok(quality) => {
+                return Ok(CheckResult {
+                    check_name: talent_name.to_string(),
+                    character_name: character.get_name().to_string(),
+                    attributes: Vec::new(),
+                    facilitation,
+                    check_type: CheckType::PointsCheck(character.get_skill_level(talent_name)),
+                    crit_type: CritType::NoCrits,
+                    rolls: Vec::new(),
+                    rerolls: Vec::new(),
+                    crit_rolls: Vec::new(),
+                    passed: true,
+                    quality,
+                    crit_successes: 0,
+                    unconfirmed_crit_successes: 0,
+                    crit_failures: 0,
+                    unconfirmed_crit_failures: 0,
+                    routine: true,
+                    notes,
+                    pass_notes: Vec::new(),
+                });
+            }
+            Err(reason) => {
+                notes.push(format!(
+                    "Routine check not possible ({}), rolling instead",
+                    reason
+                ));
+            }
         }
-    };
-    let attrs: Vec<(&str, i64)> = skill_attrs
+    }
+
+    let mut result = roll_talent_check(
+        talent_name,
+        talent_attrs,
+        character,
+        dsa_data,
+        config,
+        facilitation,
+    );
+    notes.append(&mut result.notes);
+    result.notes = notes;
+    Ok(result)
+}
+
+/*
+Checks whether a routine check (Routineprobe) is possible for the given talent.
+Returns the resulting quality level or the reason why the character does not qualify
+*/
+fn routine_check_quality(
+    talent_name: &str,
+    talent_attrs: &[String],
+    character: &Character,
+    facilitation: &Facilitation,
+) -> Result<u32, String> {
+    for attr in talent_attrs {
+        let level = character.get_attribute_level(attr);
+        if level < ROUTINE_MIN_ATTRIBUTE {
+            return Err(format!(
+                "{} is {}, but at least {} is required",
+                uppercase_first(attr),
+                level,
+                ROUTINE_MIN_ATTRIBUTE
+            ));
+        }
+    }
+
+    let modifier = facilitation.flat_facilitation;
+    if modifier < -3 {
+        return Err(format!(
+            "obstruction of {} exceeds the maximum of 3",
+            -modifier
+        ));
+    }
+    // The required skill level goes from 19 (obstruction of 3) to 1 (facilitation of 3)
+    let required_level = max(1, 10 - 3 * modifier);
+    let skill_level = character.get_skill_level(talent_name) + facilitation.points_bonus;
+    if skill_level < required_level {
+        return Err(format!(
+            "skill level is {}, but at least {} is required for a modifier of {}",
+            skill_level, required_level, modifier
+        ));
+    }
+
+    // The quality level is half (rounded up) of the quality level for the skill level as remaining points
+    let full_quality = std::cmp::min(6, max(1, (skill_level + 2) / 3));
+    Ok(((full_quality + 1) / 2) as u32)
+}
+
+/*
+Rolls a check for an already matched talent, this is shared by talent checks and extended checks
+*/
+pub fn roll_talent_check(
+    talent_name: &str,
+    talent_attrs: &[String],
+    character: &Character,
+    dsa_data: &DSAData,
+    config: &Config,
+    facilitation: Facilitation,
+) -> CheckResult {
+    let attrs: Vec<(&str, i64)> = talent_attrs
         .iter()
         .map(|attr| {
             (
@@ -114,7 +281,7 @@ pub fn talent_check(
             )
         })
         .collect();
-    let skill_level = character.get_skill_level(&talent_name);
+    let skill_level = character.get_skill_level(talent_name);
 
     let crit_type = match config.dsa_rules.crit_rules {
         config::ConfigDSACritType::NoCrits => CritType::NoCrits,
@@ -122,15 +289,37 @@ pub fn talent_check(
         config::ConfigDSACritType::AlternativeCrits => CritType::ConfirmableCrits,
     };
 
+    let rerolls: Vec<(&str, &RerollType)> = character
+        .get_talent_advantages(talent_name)
+        .filter_map(|id| {
+            dsa_data
+                .advantages
+                .get(&id.to_lowercase())
+                .and_then(|a| a.reroll.as_ref())
+                .map(|r| (id, r))
+        })
+        .chain(
+            character
+                .get_talent_disadvantages(talent_name)
+                .filter_map(|id| {
+                    dsa_data
+                        .disadvantages
+                        .get(&id.to_lowercase())
+                        .and_then(|a| a.reroll.as_ref())
+                        .map(|r| (id, r))
+                }),
+        )
+        .collect();
+
     roll_check(
         &attrs,
-        &talent_name,
+        talent_name,
         character.get_name(),
         facilitation,
         CheckType::PointsCheck(skill_level),
         crit_type,
-        output,
-    );
+        &rerolls,
+    )
 }
 
 pub fn attack_check(
@@ -138,42 +327,200 @@ pub fn attack_check(
     character: &Character,
     dsa_data: &DSAData,
     _: &Config,
-    output: &mut impl OutputWrapper,
-) {
-    let (technique_name, ranged) = match DSAData::match_search(
+) -> Result<CheckResult, Error> {
+    let (technique_name, ranged) = DSAData::match_search(
         dsa_data
             .combat_techniques
             .iter()
             .map(|(k, entry)| (k, entry.ranged))
-            // Improve: Whether a technique is ranged or not should be retrievable
-            // from the json file for custom techniques.
-            .chain(character.get_custom_techniques().map(|t| (t, false))),
+            .chain(
+                character
+                    .get_custom_techniques()
+                    .map(|(name, ranged, _)| (name, ranged)),
+            ),
         cmd_matches.value_of("technique_name").unwrap(),
-    ) {
-        Ok(t) => t,
-        Err(e) => {
-            output.output_line(&e);
-            return;
+    )?;
+    let mut facilitation = get_facilitation(cmd_matches, &["attack"])?;
+    let mut notes: Vec<String> = Vec::new();
+    let mut pass_notes: Vec<String> = Vec::new();
+
+    if let Some(m) = cmd_matches.value_of("maneuver") {
+        let (maneuver_name, level, maneuver) = get_maneuver(m, character, dsa_data)?;
+        facilitation.add(-level * maneuver.attack_penalty);
+        notes.push(format!(
+            "Maneuver: {} {} (AT -{})",
+            uppercase_first(maneuver_name),
+            level,
+            level * maneuver.attack_penalty
+        ));
+        if maneuver.damage_bonus != 0 {
+            pass_notes.push(format!(
+                "Damage +{} ({})",
+                level * maneuver.damage_bonus,
+                uppercase_first(maneuver_name)
+            ));
         }
-    };
-    let facilitation = match get_facilitation(cmd_matches, &["attack"]) {
-        Ok(f) => f,
-        Err(e) => {
-            output.output_line(&e);
-            return;
+        if maneuver.defense_penalty != 0 {
+            pass_notes.push(format!(
+                "Defense of the defender -{}",
+                level * maneuver.defense_penalty
+            ));
         }
-    };
+    }
+
+    let ranged_modifiers = get_ranged_modifiers(cmd_matches)?;
+    if !ranged_modifiers.is_empty() && !ranged {
+        return Err(Error::new(
+            format!(
+                "Ranged combat modifiers can't be used for the melee technique {}",
+                uppercase_first(technique_name)
+            ),
+            ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+        ));
+    }
+    for (name, attack_modifier, damage_modifier) in ranged_modifiers {
+        facilitation.add(attack_modifier);
+        notes.push(format!("{}: AT {:+}", name, attack_modifier));
+        if damage_modifier != 0 {
+            pass_notes.push(format!("Damage {:+} ({})", damage_modifier, name));
+        }
+    }
 
     let attack_level = character.get_attack_level(technique_name, ranged);
-    roll_check(
+    let mut result = roll_check(
         &[(technique_name, attack_level)],
         &format!("Attack: {}", technique_name),
         character.get_name(),
         facilitation,
         CheckType::SimpleCheck,
         CritType::ConfirmableCrits,
-        output,
+        &[],
     );
+    result.notes.splice(0..0, notes);
+    result.pass_notes = pass_notes;
+    Ok(result)
+}
+
+/*
+Collects the modifiers of a ranged attack given by the command arguments.
+Returns (name, attack modifier, damage modifier) for each modifier
+*/
+fn get_ranged_modifiers(cmd_matches: &ArgMatches) -> Result<Vec<(String, i64, i64)>, Error> {
+    let mut modifiers: Vec<(String, i64, i64)> = Vec::new();
+    if let Some(distance) = cmd_matches.value_of("distance") {
+        // The range band also changes the damage of the attack
+        let (attack_modifier, damage_modifier) = match distance {
+            "close" => (2, 1),
+            "medium" => (0, 0),
+            "far" => (-2, -1),
+            _ => unreachable!(),
+        };
+        modifiers.push((
+            format!("Distance {}", distance),
+            attack_modifier,
+            damage_modifier,
+        ));
+    }
+    if let Some(size) = cmd_matches.value_of("size") {
+        let attack_modifier = match size {
+            "tiny" => -8,
+            "small" => -4,
+            "medium" => 0,
+            "large" => 4,
+            "huge" => 8,
+            _ => unreachable!(),
+        };
+        modifiers.push((format!("Target size {}", size), attack_modifier, 0));
+    }
+    if cmd_matches.is_present("moving") {
+        modifiers.push((String::from("Moving target"), MOVING_TARGET_PENALTY, 0));
+    }
+    if let Some(visibility) = cmd_matches.value_of("visibility") {
+        let level = parse_ranged_level(visibility, "visibility", MAX_VISIBILITY_LEVEL)?;
+        modifiers.push((
+            format!("Visibility {}", level),
+            -level * VISIBILITY_PENALTY,
+            0,
+        ));
+    }
+    if let Some(aim) = cmd_matches.value_of("aim") {
+        let actions = parse_ranged_level(aim, "aim", MAX_AIM_ACTIONS)?;
+        modifiers.push((format!("Aiming {}", actions), actions * AIM_BONUS, 0));
+    }
+    Ok(modifiers)
+}
+
+fn parse_ranged_level(value: &str, arg: &str, max_level: i64) -> Result<i64, Error> {
+    match value.parse::<i64>() {
+        Ok(level) if (1..=max_level).contains(&level) => Ok(level),
+        _ => Err(Error::new(
+            format!(
+                "Unable to parse {}: Argument must be an integer between 1 and {}",
+                arg, max_level
+            ),
+            ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+        )),
+    }
+}
+
+/*
+Parses a maneuver argument of the form "name:level" (the level is optional and defaults to 1)
+and checks that the character is able to use the maneuver at that level
+*/
+fn get_maneuver<'a>(
+    maneuver: &str,
+    character: &Character,
+    dsa_data: &'a DSAData,
+) -> Result<(&'a str, i64, &'a CombatManeuverConfig), Error> {
+    let (search, level) = match maneuver.split_once(':') {
+        None => (maneuver, 1),
+        Some((search, level)) => match level.parse::<i64>() {
+            Ok(l) if l > 0 => (search, l),
+            _ => {
+                return Err(Error::new(
+                    "Unable to parse maneuver level: Level must be a positive integer",
+                    ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                ));
+            }
+        },
+    };
+    let (maneuver_name, maneuver_entry) =
+        DSAData::match_search(dsa_data.combat_maneuvers.iter(), search)?;
+    if level > maneuver_entry.max_level {
+        return Err(Error::new(
+            format!(
+                "The maneuver {} has a maximum level of {}",
+                uppercase_first(maneuver_name),
+                maneuver_entry.max_level
+            ),
+            ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+        ));
+    }
+    match character.get_special_ability_level(&maneuver_entry.special_ability) {
+        Some(ability_level) if ability_level >= level => {}
+        Some(ability_level) => {
+            return Err(Error::new(
+                format!(
+                    "{} only knows the maneuver {} up to level {}",
+                    character.get_name(),
+                    uppercase_first(maneuver_name),
+                    ability_level
+                ),
+                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+            ));
+        }
+        None => {
+            return Err(Error::new(
+                format!(
+                    "{} doesn't have the special ability required for the maneuver {}",
+                    character.get_name(),
+                    uppercase_first(maneuver_name)
+                ),
+                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+            ));
+        }
+    }
+    Ok((maneuver_name, level, maneuver_entry))
 }
 
 pub fn spell_check(
@@ -181,29 +528,16 @@ pub fn spell_check(
     character: &Character,
     dsa_data: &DSAData,
     config: &Config,
-    output: &mut impl OutputWrapper,
-) {
-    let (spell_name, spell_attrs) = match DSAData::match_search(
+) -> Result<CheckResult, Error> {
+    let (spell_name, spell_attrs) = DSAData::match_search(
         dsa_data
             .spells
             .iter()
             .map(|(k, v)| (k, &v.attributes))
             .chain(character.get_custom_spells()),
         cmd_matches.value_of("spell_name").unwrap(),
-    ) {
-        Ok(s) => s,
-        Err(e) => {
-            output.output_line(&e);
-            return;
-        }
-    };
-    let facilitation = match get_facilitation(cmd_matches, spell_attrs) {
-        Ok(f) => f,
-        Err(e) => {
-            output.output_line(&e);
-            return;
-        }
-    };
+    )?;
+    let facilitation = get_facilitation(cmd_matches, spell_attrs)?;
 
     let attrs: Vec<(&str, i64)> = spell_attrs
         .iter()
@@ -222,15 +556,15 @@ pub fn spell_check(
         config::ConfigDSACritType::AlternativeCrits => CritType::ConfirmableCrits,
     };
 
-    roll_check(
+    Ok(roll_check(
         &attrs,
         &spell_name,
         character.get_name(),
         facilitation,
         CheckType::PointsCheck(skill_level),
         crit_type,
-        output,
-    );
+        &[],
+    ))
 }
 
 pub fn chant_check(
@@ -238,29 +572,16 @@ pub fn chant_check(
     character: &Character,
     dsa_data: &DSAData,
     config: &Config,
-    output: &mut impl OutputWrapper,
-) {
-    let (chant_name, chant_attrs) = match DSAData::match_search(
+) -> Result<CheckResult, Error> {
+    let (chant_name, chant_attrs) = DSAData::match_search(
         dsa_data
             .chants
             .iter()
             .map(|(k, v)| (k, &v.attributes))
             .chain(character.get_custom_chants()),
         cmd_matches.value_of("chant_name").unwrap(),
-    ) {
-        Ok(r) => r,
-        Err(e) => {
-            output.output_line(&e);
-            return;
-        }
-    };
-    let facilitation = match get_facilitation(cmd_matches, chant_attrs) {
-        Ok(f) => f,
-        Err(e) => {
-            output.output_line(&e);
-            return;
-        }
-    };
+    )?;
+    let facilitation = get_facilitation(cmd_matches, chant_attrs)?;
 
     let attrs: Vec<(&str, i64)> = chant_attrs
         .iter()
@@ -279,41 +600,34 @@ pub fn chant_check(
         config::ConfigDSACritType::AlternativeCrits => CritType::ConfirmableCrits,
     };
 
-    roll_check(
+    Ok(roll_check(
         &attrs,
         &chant_name,
         character.get_name(),
         facilitation,
         CheckType::PointsCheck(skill_level),
         crit_type,
-        output,
-    );
+        &[],
+    ))
 }
 
 pub fn dodge_check(
     cmd_matches: &ArgMatches,
     character: &Character,
-    _: &DSAData,
+    dsa_data: &DSAData,
     _: &Config,
-    output: &mut impl OutputWrapper,
-) {
-    let facilitation = match get_facilitation(cmd_matches, &["dodge"]) {
-        Ok(f) => f,
-        Err(e) => {
-            output.output_line(&e);
-            return;
-        }
-    };
-    let dodge_level = character.get_dodge_level();
-    roll_check(
+) -> Result<CheckResult, Error> {
+    let facilitation = get_facilitation(cmd_matches, &["dodge"])?;
+    let dodge_level = character.get_dodge_level(dsa_data);
+    Ok(roll_check(
         &[("Dodge", dodge_level)],
         "Dodge",
         character.get_name(),
         facilitation,
         CheckType::SimpleCheck,
         CritType::ConfirmableCrits,
-        output,
-    );
+        &[],
+    ))
 }
 
 pub fn parry_check(
@@ -321,35 +635,30 @@ pub fn parry_check(
     character: &Character,
     dsa_data: &DSAData,
     _: &Config,
-    output: &mut impl OutputWrapper,
-) {
-    let (technique_name, technique_entry) = match DSAData::match_search(
-        dsa_data.combat_techniques.iter(),
+) -> Result<CheckResult, Error> {
+    let (technique_name, technique_attrs) = DSAData::match_search(
+        dsa_data
+            .combat_techniques
+            .iter()
+            .map(|(k, entry)| (k, &entry.attributes))
+            .chain(
+                character
+                    .get_custom_techniques()
+                    .map(|(name, _, attributes)| (name, attributes)),
+            ),
         cmd_matches.value_of("technique_name").unwrap(),
-    ) {
-        Err(e) => {
-            output.output_line(&e);
-            return;
-        }
-        Ok(r) => r,
-    };
-    let facilitation = match get_facilitation(cmd_matches, &["parry"]) {
-        Ok(f) => f,
-        Err(e) => {
-            output.output_line(&e);
-            return;
-        }
-    };
-    let parry_level = character.get_parry_level(&technique_name, &technique_entry.attributes);
-    roll_check(
+    )?;
+    let facilitation = get_facilitation(cmd_matches, &["parry"])?;
+    let parry_level = character.get_parry_level(&technique_name, technique_attrs, dsa_data);
+    Ok(roll_check(
         &[("Parry", parry_level)],
         &format!("Parry: {}", technique_name),
         character.get_name(),
         facilitation,
         CheckType::SimpleCheck,
         CritType::ConfirmableCrits,
-        output,
-    )
+        &[],
+    ))
 }
 
 pub fn roll(cmd_matches: &ArgMatches, output: &mut impl OutputWrapper) {
@@ -578,7 +887,7 @@ pub fn roll_ini(
     ini_information
 }
 
-fn get_facilitation<S>(matches: &ArgMatches, attributes: &[S]) -> Result<Facilitation, Error>
+pub fn get_facilitation<S>(matches: &ArgMatches, attributes: &[S]) -> Result<Facilitation, Error>
 where
     S: AsRef<str>,
 {
@@ -625,11 +934,15 @@ where
         }
     };
     Ok(Facilitation {
+        flat_facilitation,
         individual_facilitation,
         points_bonus,
     })
 }
-
+/*
+Rolls a check. The rerolls are (name, reroll type) pairs for advantages or disadvantages
+that require rerolling a die
+*/
 fn roll_check(
     attributes: &[(&str, i64)],
     check_name: &str,
@@ -637,43 +950,74 @@ fn roll_check(
     facilitation: Facilitation,
     check_type: CheckType,
     crit_type: CritType,
-    output: &mut impl OutputWrapper,
-) {
+    rerolls: &[(&str, &RerollType)],
+) -> CheckResult {
     let mut rng = rand::thread_rng();
     let d20 = Uniform::new_inclusive(1, 20);
 
     //Compute the rolls
-    let mut rolls: Vec<i64> = Vec::with_capacity(attributes.len());
+    let mut rolls: Vec<i64> = attributes.iter().map(|_| d20.sample(&mut rng)).collect();
+
+    //Apply the rerolls, each die is rerolled at most once
+    let mut notes: Vec<String> = Vec::new();
+    let mut reroll_results: Vec<Option<(i64, i64)>> = vec![None; rolls.len()];
+    for (name, reroll_type) in rerolls {
+        // How much each roll exceeds its (facilitated) attribute, higher is worse
+        let excess =
+            |i: usize| rolls[i] - (attributes[i].1 + facilitation.individual_facilitation[i]);
+        let candidates = (0..rolls.len()).filter(|&i| reroll_results[i].is_none());
+        let idx = match reroll_type {
+            RerollType::Worst => candidates.max_by_key(|&i| excess(i)),
+            RerollType::Best => candidates.min_by_key(|&i| excess(i)),
+        };
+        if let Some(idx) = idx {
+            let original = rolls[idx];
+            let new_roll = d20.sample(&mut rng);
+            rolls[idx] = match reroll_type {
+                RerollType::Worst => std::cmp::min(original, new_roll),
+                RerollType::Best => new_roll,
+            };
+            reroll_results[idx] = Some((original, new_roll));
+            notes.push(format!(
+                "{}: Rerolled {} ({}), using {}",
+                uppercase_first(name),
+                match reroll_type {
+                    RerollType::Worst => "the worst die",
+                    RerollType::Best => "the best die",
+                },
+                original,
+                rolls[idx]
+            ));
+        }
+    }
+
     let mut points = match check_type {
         CheckType::SimpleCheck => 0,
         CheckType::PointsCheck(avail_points) => max(0, avail_points + facilitation.points_bonus),
     };
-    for ((_, level), facilitation) in attributes
-        .iter()
-        .zip(facilitation.individual_facilitation.iter())
-    {
-        let roll = d20.sample(&mut rng);
+    for ((_, level), (facilitation, &roll)) in attributes.iter().zip(
+        facilitation
+            .individual_facilitation
+            .iter()
+            .zip(rolls.iter()),
+    ) {
         if roll != 1 {
             points = points - std::cmp::max(0, roll - (level + facilitation));
         }
-        rolls.push(roll);
     }
     //Check for crits
-    let mut crits = false;
     let mut unconfirmed_crit_succ = 0;
     let mut crit_succ = 0;
     let mut unconfirmed_crit_fail = 0;
     let mut crit_fail = 0;
-    let mut crits_row: Vec<String> = Vec::new();
+    let mut crit_rolls: Vec<Option<i64>> = Vec::new();
     match crit_type {
         CritType::NoCrits => {}
         CritType::ConfirmableCrits => {
-            crits_row.push(String::from("Crit roll:"));
             for ((_, level), &roll) in attributes.iter().zip(rolls.iter()) {
                 if roll == 1 {
                     let crit_roll = d20.sample(&mut rng);
-                    crits_row.push(crit_roll.to_string());
-                    crits = true;
+                    crit_rolls.push(Some(crit_roll));
                     if crit_roll <= *level {
                         crit_succ += 1;
                     } else {
@@ -681,15 +1025,14 @@ fn roll_check(
                     }
                 } else if roll == 20 {
                     let crit_roll = d20.sample(&mut rng);
-                    crits_row.push(crit_roll.to_string());
-                    crits = true;
+                    crit_rolls.push(Some(crit_roll));
                     if crit_roll > *level {
                         crit_fail += 1;
                     } else {
                         unconfirmed_crit_fail += 1;
                     }
                 } else {
-                    crits_row.push(String::from(""));
+                    crit_rolls.push(None);
                 }
             }
         }
@@ -704,23 +1047,74 @@ fn roll_check(
                 }
             }
             if num_succ >= num_required {
-                crits = true;
                 crit_succ = 1;
             }
             if num_fail >= num_required {
-                crits = true;
                 crit_fail = 1;
             }
         }
     };
 
-    //Output
-    match check_type {
+    let mut quality: u32 = 0;
+    if points >= 0 {
+        if let CheckType::PointsCheck(_) = check_type {
+            quality = (points as f32 / 3f32).ceil() as u32;
+            if quality == 0 {
+                quality = 1;
+            } else if quality > 6 {
+                quality = 6;
+            }
+        }
+    }
+
+    CheckResult {
+        check_name: check_name.to_string(),
+        character_name: character_name.to_string(),
+        attributes: attributes
+            .iter()
+            .map(|(name, level)| (name.to_string(), *level))
+            .collect(),
+        facilitation,
+        check_type,
+        crit_type,
+        rolls,
+        rerolls: reroll_results,
+        crit_rolls,
+        passed: points >= 0,
+        quality,
+        crit_successes: crit_succ,
+        unconfirmed_crit_successes: unconfirmed_crit_succ,
+        crit_failures: crit_fail,
+        unconfirmed_crit_failures: unconfirmed_crit_fail,
+        routine: false,
+        notes,
+        pass_notes: Vec::new(),
+    }
+}
+
+/*
+Describes the outcome of a check without any numbers, for players that shouldn't know the exact result of a secret check
+*/
+pub fn vague_result(result: &CheckResult) -> &'static str {
+    if result.crit_failures > 0 {
+        "You have a very bad feeling about this"
+    } else if !result.passed {
+        "You have a bad feeling about this"
+    } else if result.crit_successes > 0 {
+        "You have a very good feeling about this"
+    } else {
+        "You have a good feeling about this"
+    }
+}
+
+pub fn output_check(result: &CheckResult, output: &mut impl OutputWrapper) {
+    let facilitation = &result.facilitation;
+    match result.check_type {
         CheckType::SimpleCheck => {
             output.output_line(&format!(
                 "{}, Check for {}",
-                character_name,
-                uppercase_first(check_name)
+                result.character_name,
+                uppercase_first(&result.check_name)
             ));
         }
         CheckType::PointsCheck(avail_points) => {
@@ -730,25 +1124,45 @@ fn roll_check(
             };
             output.output_line(&format!(
                 "{}, Check for {} (level {})",
-                character_name,
-                uppercase_first(check_name),
+                result.character_name,
+                uppercase_first(&result.check_name),
                 level
             ));
         }
     };
     output.new_line();
+    for note in &result.notes {
+        output.output_line(note);
+    }
+    if !result.notes.is_empty() {
+        output.new_line();
+    }
+
+    if result.routine {
+        output.output_line(&format!(
+            "Routine check passed, quality level {}",
+            result.quality
+        ));
+        return;
+    }
 
     let mut table: Vec<Vec<String>> = Vec::with_capacity(2);
 
-    let mut header: Vec<String> = Vec::with_capacity(attributes.len() + 1);
+    let mut header: Vec<String> = Vec::with_capacity(result.attributes.len() + 1);
     header.push(String::from(""));
-    header.extend(attributes.iter().map(|(name, _)| uppercase_first(name)));
+    header.extend(
+        result
+            .attributes
+            .iter()
+            .map(|(name, _)| uppercase_first(name)),
+    );
     table.push(header);
 
-    let mut char_row: Vec<String> = Vec::with_capacity(attributes.len() + 1);
+    let mut char_row: Vec<String> = Vec::with_capacity(result.attributes.len() + 1);
     char_row.push(String::from("Character:"));
     char_row.extend(
-        attributes
+        result
+            .attributes
             .iter()
             .zip(facilitation.individual_facilitation.iter())
             .map(|((_, level), facilitation)| {
@@ -763,64 +1177,142 @@ fn roll_check(
     );
     table.push(char_row);
 
-    let mut rolls_row: Vec<String> = Vec::with_capacity(attributes.len() + 1);
+    let mut rolls_row: Vec<String> = Vec::with_capacity(result.attributes.len() + 1);
     rolls_row.push(String::from("Roll:"));
-    rolls_row.extend(rolls.iter().map(|roll| roll.to_string()));
+    rolls_row.extend(
+        result
+            .rolls
+            .iter()
+            .zip(result.rerolls.iter())
+            .map(|(roll, reroll)| reroll.map_or(*roll, |(original, _)| original).to_string()),
+    );
     table.push(rolls_row);
 
-    if let CritType::ConfirmableCrits = crit_type {
-        if crits {
+    if result.rerolls.iter().any(|r| r.is_some()) {
+        let mut rerolls_row: Vec<String> = Vec::with_capacity(result.attributes.len() + 1);
+        rerolls_row.push(String::from("Reroll:"));
+        rerolls_row.extend(
+            result
+                .rerolls
+                .iter()
+                .map(|r| r.map_or(String::from(""), |(_, new_roll)| new_roll.to_string())),
+        );
+        table.push(rerolls_row);
+    }
+
+    if let CritType::ConfirmableCrits = result.crit_type {
+        if result.crit_rolls.iter().any(|r| r.is_some()) {
+            let mut crits_row: Vec<String> = Vec::with_capacity(result.attributes.len() + 1);
+            crits_row.push(String::from("Crit roll:"));
+            crits_row.extend(
+                result
+                    .crit_rolls
+                    .iter()
+                    .map(|r| r.map_or(String::from(""), |r| r.to_string())),
+            );
             table.push(crits_row);
         }
     }
     output.output_table(&table);
     output.new_line();
 
-    if points < 0 {
+    if !result.passed {
         output.output_line(&"Check failed");
     } else {
-        match check_type {
+        match result.check_type {
             CheckType::SimpleCheck => {
                 output.output_line(&"Check passed");
             }
             CheckType::PointsCheck(_) => {
-                let mut quality: u32 = (points as f32 / 3f32).ceil() as u32;
-                if quality == 0 {
-                    quality = 1;
-                } else if quality > 6 {
-                    quality = 6;
-                }
-                output.output_line(&format!("Check passed, quality level {}", quality));
+                output.output_line(&format!("Check passed, quality level {}", result.quality));
             }
         }
+        for note in &result.pass_notes {
+            output.output_line(note);
+        }
+    }
+
+    if result.crit_successes == 1 {
+        output.output_line(&"Critical success");
+    } else if result.crit_successes > 1 {
+        output.output_line(&format!("{} critical successes", result.crit_successes));
+    }
+    if result.unconfirmed_crit_successes == 1 {
+        output.output_line(&"Unconfirmed critical success");
+    } else if result.unconfirmed_crit_successes > 1 {
+        output.output_line(&format!(
+            "{} unconfirmed critical successes",
+            result.unconfirmed_crit_successes
+        ));
+    }
+    if result.crit_failures == 1 {
+        output.output_line(&"Critical failure");
+    } else if result.crit_failures > 1 {
+        output.output_line(&format!("{} critical failures", result.crit_failures));
+    }
+    if result.unconfirmed_crit_failures == 1 {
+        output.output_line(&"Unconfirmed critical failure");
+    } else if result.unconfirmed_crit_failures > 1 {
+        output.output_line(&format!(
+            "{} unconfirmed critical failures",
+            result.unconfirmed_crit_failures
+        ));
     }
+}
 
-    if crits {
-        if crit_succ == 1 {
-            output.output_line(&"Critical success");
-        } else if crit_succ > 1 {
-            output.output_line(&format!("{} critical successes", crit_succ));
+/*
+Displays the results of a check that was rolled for multiple characters as a single table.
+The characters are ordered by their result, the best result first
+*/
+pub fn output_group_check(results: &mut Vec<CheckResult>, output: &mut impl OutputWrapper) {
+    let check_name = match results.first() {
+        Some(r) => uppercase_first(&r.check_name),
+        None => {
+            return;
         }
-        if unconfirmed_crit_succ == 1 {
-            output.output_line(&"Unconfirmed critical success");
-        } else if unconfirmed_crit_succ > 1 {
-            output.output_line(&format!(
-                "{} unconfirmed critical successes",
-                unconfirmed_crit_succ
-            ));
+    };
+    results.sort_by(|r1, r2| (r2.passed, r2.quality).cmp(&(r1.passed, r1.quality)));
+
+    output.output_line(&format!("Group check for {}", check_name));
+    output.new_line();
+    let mut table: Vec<Vec<String>> = Vec::with_capacity(results.len() + 1);
+    table.push(vec![
+        String::from("Character"),
+        String::from("Rolls"),
+        String::from("Result"),
+        String::from("Crits"),
+    ]);
+    for result in results.iter() {
+        let outcome = if !result.passed {
+            String::from("Failed")
+        } else if let CheckType::PointsCheck(_) = result.check_type {
+            format!("QS {}", result.quality)
+        } else {
+            String::from("Passed")
+        };
+        let mut crits: Vec<&str> = Vec::new();
+        if result.crit_successes > 0 {
+            crits.push("Success");
         }
-        if crit_fail == 1 {
-            output.output_line(&"Critical failure");
-        } else if crit_fail > 1 {
-            output.output_line(&format!("{} critical failures", crit_fail));
+        if result.unconfirmed_crit_successes > 0 {
+            crits.push("Unconfirmed success");
         }
-        if unconfirmed_crit_fail == 1 {
-            output.output_line(&"Unconfirmed critical failure");
-        } else if unconfirmed_crit_fail > 1 {
-            output.output_line(&format!(
-                "{} unconfirmed critical failures",
-                unconfirmed_crit_fail
-            ));
+        if result.crit_failures > 0 {
+            crits.push("Failure");
+        }
+        if result.unconfirmed_crit_failures > 0 {
+            crits.push("Unconfirmed failure");
         }
+        table.push(vec![
+            format!("{}:", result.character_name),
+            if result.routine {
+                String::from("Routine")
+            } else {
+                result.rolls.iter().join(" ")
+            },
+            outcome,
+            crits.join(", "),
+        ]);
     }
+    output.output_table(&table);
 }
@@ -0,0 +1,328 @@
+use super::character::Character;
+use super::config::{self, Config, DSAData};
+use super::dsa;
+use super::util::*;
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+const EXTENDED_CHECKS_FILE: &'static str = "extended_checks";
+
+/*
+The time interval between two attempts of an extended check, for example "1 hour"
+*/
+#[derive(Serialize, Deserialize)]
+struct Interval {
+    amount: u32,
+    unit: String,
+}
+
+impl Interval {
+    fn parse(interval: &str) -> Result<Interval, Error> {
+        // The unit may be separated by a space ("1 hour") or not ("1h")
+        let trimmed = interval.trim().trim_matches('"');
+        let unit_idx = trimmed
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(trimmed.len());
+        let unit = trimmed[unit_idx..].trim();
+        match trimmed[..unit_idx].parse::<u32>() {
+            Ok(amount) if !unit.is_empty() => Ok(Interval {
+                amount,
+                unit: unit.to_string(),
+            }),
+            _ => Err(Error::new(
+                format!(
+                    "Unable to parse interval \"{}\": Expected an amount and a unit, e.g. \"1 hour\" or \"1h\"",
+                    interval
+                ),
+                ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+            )),
+        }
+    }
+
+    fn times(&self, count: u32) -> String {
+        format!("{} {}", self.amount * count, self.unit)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum ExtendedCheckState {
+    InProgress,
+    Succeeded,
+    Failed,
+}
+
+/*
+An extended check (Sammelprobe), where a number of quality levels has to be accumulated
+within a maximum number of attempts
+*/
+#[derive(Serialize, Deserialize)]
+pub struct ExtendedCheck {
+    talent: String,
+    target_quality: u32,
+    max_attempts: u32,
+    interval: Interval,
+    accumulated_quality: u32,
+    attempts: u32,
+    state: ExtendedCheckState,
+}
+
+impl ExtendedCheck {
+    fn output_status(&self, character_name: &str, output: &mut impl OutputWrapper) {
+        output.output_line(&format!(
+            "{}, Extended check for {}",
+            character_name,
+            uppercase_first(&self.talent)
+        ));
+        output.new_line();
+        output.output_table(&vec![
+            vec![
+                String::from("Quality levels:"),
+                format!("{}/{}", self.accumulated_quality, self.target_quality),
+            ],
+            vec![
+                String::from("Attempts:"),
+                format!("{}/{}", self.attempts, self.max_attempts),
+            ],
+            vec![
+                String::from("Time passed:"),
+                self.interval.times(self.attempts),
+            ],
+        ]);
+        output.new_line();
+        match self.state {
+            ExtendedCheckState::InProgress => {
+                output.output_line(&"Extended check in progress");
+            }
+            ExtendedCheckState::Succeeded => {
+                output.output_line(&"Extended check succeeded");
+            }
+            ExtendedCheckState::Failed => {
+                output.output_line(&"Extended check failed");
+            }
+        }
+    }
+}
+
+/*
+Executes the "extended" command for the given character.
+The character_key identifies the character in the list of stored extended checks.
+Returns the result of the attempt, if the check was rolled
+*/
+pub async fn extended_check(
+    cmd_matches: &ArgMatches,
+    character: &Character,
+    character_key: &str,
+    dsa_data: &DSAData,
+    config: &Config,
+    output: &mut impl OutputWrapper,
+) -> Result<Option<dsa::CheckResult>, Error> {
+    let mut checks = read_extended_checks().await?;
+    match cmd_matches.value_of("action").unwrap() {
+        "start" => {
+            let talent_search = match cmd_matches.value_of("skill_name") {
+                Some(t) => t,
+                None => {
+                    return Err(Error::new(
+                        "Missing talent: Use \"extended start [TALENT]\" to start an extended check",
+                        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                    ));
+                }
+            };
+            let (talent_name, _) = DSAData::match_search(
+                dsa_data
+                    .talents
+                    .iter()
+                    .map(|(k, v)| (k, &v.attributes))
+                    .chain(character.get_custom_talents()),
+                talent_search,
+            )?;
+            let target_quality = parse_positive(cmd_matches, "target")?;
+            let max_attempts = parse_positive(cmd_matches, "attempts")?;
+            let interval = match cmd_matches.value_of("interval") {
+                Some(i) => Interval::parse(i)?,
+                None => {
+                    return Err(missing_argument_err("interval"));
+                }
+            };
+            let check = ExtendedCheck {
+                talent: talent_name.to_string(),
+                target_quality,
+                max_attempts,
+                interval,
+                accumulated_quality: 0,
+                attempts: 0,
+                state: ExtendedCheckState::InProgress,
+            };
+            check.output_status(character.get_name(), output);
+            checks.insert(character_key.to_string(), check);
+            write_extended_checks(&checks).await?;
+        }
+        "roll" => {
+            let check = match checks.get_mut(character_key) {
+                Some(c) => c,
+                None => {
+                    return Err(no_extended_check_err());
+                }
+            };
+            // The talent name is optional when rolling, it comes before the facilitation
+            if let Some(talent_search) = cmd_matches.value_of("skill_name") {
+                let (talent_name, _) = DSAData::match_search(
+                    dsa_data
+                        .talents
+                        .iter()
+                        .map(|(k, v)| (k, &v.attributes))
+                        .chain(character.get_custom_talents()),
+                    talent_search,
+                )?;
+                if talent_name != check.talent {
+                    return Err(Error::new(
+                        format!(
+                            "The running extended check is for {}, not {}",
+                            uppercase_first(&check.talent),
+                            uppercase_first(talent_name)
+                        ),
+                        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                    ));
+                }
+            }
+            if !matches!(check.state, ExtendedCheckState::InProgress) {
+                return Err(Error::new(
+                    "The extended check is already finished, use \"extended start\" to begin a new one",
+                    ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                ));
+            }
+            // The talent is either a built-in talent or a custom talent of the character
+            let talent_attrs = match dsa_data
+                .talents
+                .get(&check.talent)
+                .map(|t| &t.attributes)
+                .or_else(|| {
+                    character
+                        .get_custom_talents()
+                        .find(|(name, _)| **name == check.talent)
+                        .map(|(_, attributes)| attributes)
+                }) {
+                Some(t) => t,
+                None => {
+                    return Err(Error::new(
+                        format!("Unknown talent \"{}\"", check.talent),
+                        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                    ));
+                }
+            };
+            let facilitation = dsa::get_facilitation(cmd_matches, talent_attrs)?;
+            let result = dsa::roll_talent_check(
+                &check.talent,
+                talent_attrs,
+                character,
+                dsa_data,
+                config,
+                facilitation,
+            );
+            dsa::output_check(&result, output);
+            output.new_line();
+
+            check.attempts += 1;
+            if result.passed {
+                // A critical success doubles the quality level of this attempt
+                check.accumulated_quality += if result.crit_successes > 0 {
+                    2 * result.quality
+                } else {
+                    result.quality
+                };
+            }
+            if result.crit_failures > 0 {
+                // A critical failure ends the extended check
+                check.state = ExtendedCheckState::Failed;
+            } else if check.accumulated_quality >= check.target_quality {
+                check.state = ExtendedCheckState::Succeeded;
+            } else if check.attempts >= check.max_attempts {
+                check.state = ExtendedCheckState::Failed;
+            }
+            check.output_status(character.get_name(), output);
+            write_extended_checks(&checks).await?;
+            return Ok(Some(result));
+        }
+        "status" => match checks.get(character_key) {
+            Some(check) => {
+                check.output_status(character.get_name(), output);
+            }
+            None => {
+                return Err(no_extended_check_err());
+            }
+        },
+        _ => unreachable!(),
+    };
+    Ok(None)
+}
+
+// Removes the extended checks of the given characters, e.g. after the characters were deleted
+pub async fn remove_extended_checks(character_keys: &[String]) -> Result<(), Error> {
+    let mut checks = read_extended_checks().await?;
+    let num_checks = checks.len();
+    checks.retain(|key, _| !character_keys.contains(key));
+    if checks.len() != num_checks {
+        write_extended_checks(&checks).await?;
+    }
+    Ok(())
+}
+
+fn no_extended_check_err() -> Error {
+    Error::new(
+        "No extended check found for this character, use \"extended start\" to begin one",
+        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+    )
+}
+
+fn missing_argument_err(arg: &str) -> Error {
+    Error::new(
+        format!(
+            "Missing argument: Starting an extended check requires --{}",
+            arg
+        ),
+        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+    )
+}
+
+fn parse_positive(cmd_matches: &ArgMatches, arg: &str) -> Result<u32, Error> {
+    let value = match cmd_matches.value_of(arg) {
+        Some(v) => v,
+        None => {
+            return Err(missing_argument_err(arg));
+        }
+    };
+    match value.parse::<u32>() {
+        Ok(num) if num > 0 => Ok(num),
+        _ => Err(Error::new(
+            format!(
+                "Unable to parse {}: Argument must be a positive integer",
+                arg
+            ),
+            ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+        )),
+    }
+}
+
+async fn read_extended_checks() -> Result<HashMap<String, ExtendedCheck>, Error> {
+    let path = get_extended_checks_path()?;
+    let is_valid =
+        |data: &[u8]| serde_json::from_slice::<HashMap<String, ExtendedCheck>>(data).is_ok();
+    match read_file_recovering_async(path, is_valid).await? {
+        Some(data) => Ok(serde_json::from_slice(&data)?),
+        None => Ok(HashMap::new()),
+    }
+}
+
+async fn write_extended_checks(checks: &HashMap<String, ExtendedCheck>) -> Result<(), Error> {
+    let path = get_extended_checks_path()?;
+    let data = serde_json::to_vec(checks)?;
+    write_file_atomic_async(path, data).await
+}
+
+fn get_extended_checks_path() -> Result<PathBuf, Error> {
+    let mut path = config::get_config_dir()?;
+    path.push(EXTENDED_CHECKS_FILE);
+    Ok(path)
+}
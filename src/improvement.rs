@@ -0,0 +1,254 @@
+use super::character::Character;
+use super::character_edit::{self, CharacterChange};
+use super::config::DSAData;
+use super::util::*;
+use clap::ArgMatches;
+use serde_json::{json, Value};
+
+// The improvement column of attributes, which is not configured in the dsa data
+const ATTRIBUTE_IMPROVEMENT: char = 'E';
+// The improvement column of custom entries that are not part of the dsa data
+const CUSTOM_IMPROVEMENT: char = 'B';
+// Up to these levels, every step costs the base cost of the column
+const SKILL_COST_THRESHOLD: i64 = 12;
+const ATTRIBUTE_COST_THRESHOLD: i64 = 14;
+
+/*
+Executes the "improve" command on the raw character data: Raises an attribute,
+talent, combat technique, spell or chant and pays for it with the unspent AP of the character
+*/
+pub fn improve_command(
+    cmd_matches: &ArgMatches,
+    raw: &[u8],
+    dsa_data: &DSAData,
+    output: &mut impl OutputWrapper,
+) -> Result<Vec<u8>, Error> {
+    let character = Character::from_raw(raw.to_vec())?;
+    let amount: i64 = match cmd_matches.value_of("amount") {
+        None => 1,
+        Some(a) => match a.trim_start_matches('+').parse() {
+            Ok(a) if a > 0 => a,
+            _ => {
+                return Err(Error::new(
+                    "Unable to parse amount: Argument must be a positive integer",
+                    ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                ));
+            }
+        },
+    };
+    let (name, kind) = character_edit::find_entry(
+        &character_edit::ALL_KINDS,
+        cmd_matches.value_of("name").unwrap(),
+        Some(&character),
+        dsa_data,
+    )?;
+
+    let old_level = match kind {
+        "attribute" => character.get_attribute_level(name),
+        "talent" => character.get_skill_level(name),
+        "technique" => character.get_technique_level(name),
+        "spell" => character.get_spell_level(name),
+        "chant" => character.get_chant_level(name),
+        _ => unreachable!(),
+    };
+    let new_level = old_level + amount;
+    let cost = improvement_cost(
+        kind,
+        get_improvement(kind, name, dsa_data),
+        old_level,
+        new_level,
+    );
+    let (_, unspent) = character.get_ap();
+    if cost > unspent {
+        return Err(Error::new(
+            format!(
+                "Raising {} {} to {} costs {} AP, but {} only has {} AP left",
+                kind,
+                uppercase_first(name),
+                new_level,
+                cost,
+                character.get_name(),
+                unspent
+            ),
+            ErrorType::InvalidInput(InputErrorType::InsufficientAP),
+        ));
+    }
+
+    let mut value: Value = serde_json::from_slice(raw)?;
+    character_edit::set_level(&mut value, character_edit::kind_key(kind), name, new_level)?;
+    let (total, spent) = get_ap_values(&value);
+    value["ap"] = json!({ "total": total, "spent": spent + cost });
+    let change = CharacterChange {
+        kind: kind.to_string(),
+        name: name.to_string(),
+        old_level: Some(old_level),
+        new_level,
+    };
+    character_edit::record_change(&mut value, &change)?;
+    change.output(character.get_name(), output);
+    output.output_line(&format!(
+        "Cost: {} AP, {} AP remaining",
+        cost,
+        unspent - cost
+    ));
+    Ok(serde_json::to_vec_pretty(&value)?)
+}
+
+/*
+Executes the "ap" command on the raw character data. Either shows the AP of the character
+or awards new AP to it. The data is only changed in the latter case
+*/
+pub fn ap_command(
+    cmd_matches: &ArgMatches,
+    raw: &[u8],
+    _: &DSAData,
+    output: &mut impl OutputWrapper,
+) -> Result<Vec<u8>, Error> {
+    let character = Character::from_raw(raw.to_vec())?;
+    let (total, unspent) = character.get_ap();
+    match cmd_matches.value_of("action") {
+        Some("add") => {
+            let amount: i64 = match cmd_matches.value_of("amount").map(|a| a.parse()) {
+                Some(Ok(a)) if a > 0 => a,
+                Some(_) => {
+                    return Err(Error::new(
+                        "Unable to parse amount: Argument must be a positive integer",
+                        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                    ));
+                }
+                None => {
+                    return Err(Error::new(
+                        "Missing amount of AP to add",
+                        ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                    ));
+                }
+            };
+            let mut value: Value = serde_json::from_slice(raw)?;
+            let (_, spent) = get_ap_values(&value);
+            value["ap"] = json!({ "total": total + amount, "spent": spent });
+            character_edit::record_change(
+                &mut value,
+                &CharacterChange {
+                    kind: String::from("ap"),
+                    name: String::from("total"),
+                    old_level: Some(total),
+                    new_level: total + amount,
+                },
+            )?;
+            output.output_line(&format!(
+                "{}: Added {} AP, {} AP unspent ({} AP total)",
+                character.get_name(),
+                amount,
+                unspent + amount,
+                total + amount
+            ));
+            Ok(serde_json::to_vec_pretty(&value)?)
+        }
+        _ => {
+            output.output_line(&format!(
+                "{}: {} AP unspent ({} AP total)",
+                character.get_name(),
+                unspent,
+                total
+            ));
+            Ok(raw.to_vec())
+        }
+    }
+}
+
+/*
+Executes the "cost" command, which shows the AP cost of raising
+a value from one level to another without changing any character
+*/
+pub fn cost_command(
+    cmd_matches: &ArgMatches,
+    dsa_data: &DSAData,
+    output: &mut impl OutputWrapper,
+) -> Result<(), Error> {
+    let (name, kind) = character_edit::find_entry(
+        &character_edit::ALL_KINDS,
+        cmd_matches.value_of("name").unwrap(),
+        None,
+        dsa_data,
+    )?;
+    let mut levels = Vec::new();
+    for arg in &["from", "to"] {
+        match cmd_matches.value_of(arg).unwrap().parse::<i64>() {
+            Ok(l) if l >= 0 => levels.push(l),
+            _ => {
+                return Err(Error::new(
+                    format!(
+                        "Unable to parse {}: Argument must be a non-negative integer",
+                        arg
+                    ),
+                    ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+                ));
+            }
+        }
+    }
+    let (from, to) = (levels[0], levels[1]);
+    if to < from {
+        return Err(Error::new(
+            "The target level must not be lower than the current level",
+            ErrorType::InvalidInput(InputErrorType::InvalidArgument),
+        ));
+    }
+    let improvement = get_improvement(kind, name, dsa_data);
+    output.output_line(&format!(
+        "Raising {} {} (column {}) from {} to {} costs {} AP",
+        kind,
+        uppercase_first(name),
+        improvement,
+        from,
+        to,
+        improvement_cost(kind, improvement, from, to)
+    ));
+    Ok(())
+}
+
+/*
+Returns the AP needed to raise a value of the given kind and improvement column from one level to another.
+Every step costs the base cost of the column, steps above the threshold cost a multiple of it
+*/
+pub fn improvement_cost(kind: &str, improvement: char, from: i64, to: i64) -> i64 {
+    let base_cost = match improvement {
+        'A' => 1,
+        'B' => 2,
+        'C' => 3,
+        'D' => 4,
+        _ => 15,
+    };
+    let threshold = if kind == "attribute" {
+        ATTRIBUTE_COST_THRESHOLD
+    } else {
+        SKILL_COST_THRESHOLD
+    };
+    ((from + 1)..=to)
+        .map(|level| base_cost * std::cmp::max(1, level - threshold + 1))
+        .sum()
+}
+
+// Returns the improvement column of the entry with the given name and kind
+pub fn get_improvement(kind: &str, name: &str, dsa_data: &DSAData) -> char {
+    let improvement = match kind {
+        "attribute" => Some(ATTRIBUTE_IMPROVEMENT),
+        "talent" => dsa_data.talents.get(name).map(|t| t.improvement),
+        "technique" => dsa_data.combat_techniques.get(name).map(|t| t.improvement),
+        "spell" => dsa_data.spells.get(name).map(|s| s.improvement),
+        "chant" => dsa_data.chants.get(name).map(|c| c.improvement),
+        _ => unreachable!(),
+    };
+    improvement.unwrap_or(CUSTOM_IMPROVEMENT)
+}
+
+// Returns the total and spent AP stored in the raw character data
+fn get_ap_values(value: &Value) -> (i64, i64) {
+    let get = |key: &str| {
+        value
+            .get("ap")
+            .and_then(|ap| ap.get(key))
+            .and_then(|v| v.as_i64())
+            .unwrap_or(0)
+    };
+    (get("total"), get("spent"))
+}
@@ -1,11 +1,20 @@
 mod character;
+mod character_creation;
+mod character_edit;
+mod character_history;
 mod character_manager;
+mod character_sheet;
+mod character_storage;
 mod cli;
 mod config;
 mod discord;
 mod discord_commands;
 mod dsa;
+mod extended_check;
 mod greet;
+mod improvement;
+mod optolith;
+mod party;
 mod util;
 
 #[macro_use]
@@ -14,10 +23,14 @@ extern crate enum_display_derive;
 use character::Character;
 use clap::ArgMatches;
 use config::{AbstractConfig, Config, DSAData};
+use dsa::CheckResult;
+use std::path::{Path, PathBuf};
 use tokio::runtime::Builder;
 use util::{Error, OutputWrapper};
 
-use crate::util::ErrorType;
+use crate::util::{ErrorType, InputErrorType};
+
+const IMPORTED_CHARACTER_FILE: &'static str = "imported_character";
 
 fn main() {
     let mut output = util::CLIOutputWrapper {};
@@ -74,7 +87,42 @@ async fn parse_local_command(
 ) {
     match matches.subcommand() {
         Some(("load", sub_m)) => {
-            let character = match Character::load(sub_m.value_of("character_path").unwrap()).await {
+            let mut character_path = PathBuf::from(sub_m.value_of("character_path").unwrap());
+            let dsa_data = match get_dsa_data(&config, &mut output) {
+                Ok(data) => data,
+                Err(e) => {
+                    output.output_line(&format!("Error retrieving dsa data: {}", e));
+                    return;
+                }
+            };
+            // Optolith characters are converted and the converted character is loaded instead
+            match import_optolith_character(&character_path, &dsa_data).await {
+                Ok(Some((imported_path, unmapped))) => {
+                    optolith::output_import_report(&unmapped, &mut output);
+                    character_path = imported_path;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    output.output_line(&format!("Error loading character: {}", e.message()));
+                    return;
+                }
+            };
+            match Character::from_file(&character_path).await {
+                Ok(character) => {
+                    let report = character.validate(&dsa_data);
+                    report.output(&mut output);
+                    if report.is_fatal() {
+                        output
+                            .output_line(&"Character was not loaded, please fix the errors above");
+                        return;
+                    }
+                }
+                Err(e) => {
+                    output.output_line(&format!("Error loading character: {}", e.message()));
+                    return;
+                }
+            };
+            let character = match Character::load(&character_path).await {
                 Ok(c) => c,
                 Err(e) => {
                     output.output_line(&format!("Error loading character: {}", e.message()));
@@ -129,32 +177,97 @@ async fn parse_local_command(
             execute_character_command(&dsa::parry_check, &sub_m, &config, &mut output).await;
         }
 
+        Some(("extended", sub_m)) => {
+            if let Some((character, dsa_data)) =
+                try_get_character_and_dsa_data(&config, &mut output).await
+            {
+                // There is only one loaded character, so its name identifies it
+                let character_key = format!("local:{}", character.get_name());
+                if let Err(e) = extended_check::extended_check(
+                    sub_m,
+                    &character,
+                    &character_key,
+                    &dsa_data,
+                    &config,
+                    &mut output,
+                )
+                .await
+                {
+                    output.output_line(&e);
+                }
+            }
+        }
+
+        Some(("sheet", sub_m)) => {
+            if let Some((character, dsa_data)) =
+                try_get_character_and_dsa_data(&config, &mut output).await
+            {
+                character_sheet::character_sheet(sub_m, &character, &dsa_data, &mut output);
+            }
+        }
+
+        Some(("create", sub_m)) => {
+            if let Ok(dsa_data) = get_dsa_data(&config, &mut output) {
+                if let Err(e) = create_character(sub_m, &dsa_data, &mut output) {
+                    output.output_line(&format!("Error creating character: {}", e));
+                }
+            }
+        }
+
+        Some(("migrate-storage", sub_m)) => {
+            if let Err(e) = migrate_storage(sub_m, &mut output).await {
+                output.output_line(&format!("Error migrating characters: {}", e));
+            }
+        }
+
+        Some(("set", sub_m)) => {
+            if let Err(e) =
+                edit_character(sub_m, &config, &mut output, character_edit::set_command).await
+            {
+                output.output_line(&format!("Error changing character: {}", e));
+            }
+        }
+
+        Some(("improve", sub_m)) => {
+            if let Err(e) =
+                edit_character(sub_m, &config, &mut output, improvement::improve_command).await
+            {
+                output.output_line(&format!("Error improving character: {}", e));
+            }
+        }
+
+        Some(("ap", sub_m)) => {
+            if let Err(e) =
+                edit_character(sub_m, &config, &mut output, improvement::ap_command).await
+            {
+                output.output_line(&format!("Error changing AP: {}", e));
+            }
+        }
+
+        Some(("cost", sub_m)) => {
+            if let Ok(dsa_data) = get_dsa_data(&config, &mut output) {
+                if let Err(e) = improvement::cost_command(sub_m, &dsa_data, &mut output) {
+                    output.output_line(&e);
+                }
+            }
+        }
+
         Some(("roll", sub_m)) => {
             dsa::roll(sub_m, &mut output);
         }
 
         Some(("ini", _)) => {
-            let character = match Character::loaded_character().await {
-                Ok(Some(c)) => c,
-                Ok(None) => {
-                    output.output_line(&"Error: No character loaded");
-                    return;
-                }
-                Err(e) => {
-                    output.output_line(&format!(
-                        "Error retrieving loaded character: {}",
-                        e.message()
-                    ));
-                    return;
-                }
-            };
-            dsa::roll_ini(
-                &[(
-                    character.get_name().to_string(),
-                    character.get_initiative_level(),
-                )],
-                &mut output,
-            );
+            if let Some((character, dsa_data)) =
+                try_get_character_and_dsa_data(&config, &mut output).await
+            {
+                dsa::roll_ini(
+                    &[(
+                        character.get_name().to_string(),
+                        character.get_initiative_level(&dsa_data),
+                    )],
+                    &mut output,
+                );
+            }
         }
 
         Some(("hi", _)) => {
@@ -167,6 +280,106 @@ async fn parse_local_command(
     };
 }
 
+/*
+Copies the characters of the discord bot from one storage to another.
+The bot only uses the new storage after the config is changed accordingly
+*/
+async fn migrate_storage(
+    matches: &ArgMatches,
+    output: &mut impl OutputWrapper,
+) -> Result<(), Error> {
+    let storage_type = |arg: &str| match matches.value_of(arg).unwrap() {
+        "SQLite" => config::ConfigStorageType::SQLite,
+        _ => config::ConfigStorageType::File,
+    };
+    let from = character_storage::open_storage(&storage_type("from"))?;
+    let to = character_storage::open_storage(&storage_type("to"))?;
+    let num_characters = character_storage::migrate_storage(from.as_ref(), to.as_ref()).await?;
+    output.output_line(&format!(
+        "Copied {} characters, set \"storage\" in the discord config to \"{}\" to use them",
+        num_characters,
+        matches.value_of("to").unwrap()
+    ));
+    Ok(())
+}
+
+/*
+Runs the character creation wizard on the command line and saves the new character
+to the current working directory. Entering "cancel" aborts the creation
+*/
+fn create_character(
+    matches: &ArgMatches,
+    dsa_data: &DSAData,
+    output: &mut impl OutputWrapper,
+) -> Result<(), Error> {
+    let mut wizard = character_creation::CharacterWizard::new(matches, None)?;
+    output.output_line(&"Creating a new character, enter \"cancel\" to abort");
+    while !wizard.is_done() {
+        output.output_line(&wizard.prompt(dsa_data));
+        let mut input = String::new();
+        if std::io::stdin().read_line(&mut input)? == 0 || input.trim() == "cancel" {
+            output.output_line(&"Character creation cancelled");
+            return Ok(());
+        }
+        if let Err(e) = wizard.handle_input(&input, dsa_data) {
+            output.output_line(&e);
+        }
+    }
+    output.output_line(&wizard.prompt(dsa_data));
+    output.output_file(
+        &format!("{}.tdc", util::file_name(wizard.get_name())),
+        wizard.to_raw(dsa_data)?,
+    );
+    output.output_line(&"Use the \"load\" command to use the new character");
+    Ok(())
+}
+
+/*
+Changes the loaded character by applying the given edit function to its file
+*/
+async fn edit_character<O: OutputWrapper>(
+    matches: &ArgMatches,
+    config: &Config,
+    output: &mut O,
+    edit: fn(&ArgMatches, &[u8], &DSAData, &mut O) -> Result<Vec<u8>, Error>,
+) -> Result<(), Error> {
+    let path = match Character::loaded_character_path()? {
+        Some(p) => p,
+        None => {
+            return Err(Error::new(
+                "No character loaded",
+                ErrorType::InvalidInput(InputErrorType::MissingCharacter),
+            ));
+        }
+    };
+    let dsa_data = get_dsa_data(config, output)?;
+    let raw = tokio::fs::read(&path).await?;
+    let raw = edit(matches, &raw, &dsa_data, output)?;
+    // The file belongs to the user, so it is replaced safely but without leaving a backup next to it
+    util::replace_file_async(path, raw).await?;
+    Ok(())
+}
+
+/*
+Converts the character at the given path, if it is an Optolith export.
+Returns the path of the converted character and all entries that could not be mapped
+*/
+async fn import_optolith_character(
+    path: &Path,
+    dsa_data: &DSAData,
+) -> Result<Option<(PathBuf, Vec<String>)>, Error> {
+    let raw = tokio::fs::read(path).await?;
+    match optolith::import_character(&raw, dsa_data)? {
+        Some((converted, unmapped)) => {
+            let mut imported_path = config::get_config_dir()?;
+            imported_path.push(IMPORTED_CHARACTER_FILE);
+            util::write_file_atomic_async(imported_path.clone(), converted).await?;
+            Ok(Some((imported_path, unmapped)))
+        }
+        None => Ok(None),
+    }
+}
+
 fn get_dsa_data(config: &Config, output: &mut impl OutputWrapper) -> Result<DSAData, Error> {
     let dsa_data = match DSAData::get_or_create(output) {
         Ok(d) => d,
@@ -187,16 +400,17 @@ fn get_dsa_data(config: &Config, output: &mut impl OutputWrapper) -> Result<DSAD
     Ok(dsa_data)
 }
 
-async fn execute_character_command<O>(
-    check_fn: impl Fn(&ArgMatches, &Character, &DSAData, &Config, &mut O),
+async fn execute_character_command(
+    check_fn: impl Fn(&ArgMatches, &Character, &DSAData, &Config) -> Result<CheckResult, Error>,
     matches: &ArgMatches,
     config: &Config,
-    output: &mut O,
-) where
-    O: OutputWrapper,
-{
+    output: &mut impl OutputWrapper,
+) {
     if let Some((character, dsa_data)) = try_get_character_and_dsa_data(config, output).await {
-        check_fn(matches, &character, &dsa_data, config, output);
+        match check_fn(matches, &character, &dsa_data, config) {
+            Ok(result) => dsa::output_check(&result, output),
+            Err(e) => output.output_line(&e),
+        }
     } else {
         return;
     }
@@ -0,0 +1,207 @@
+use super::config::DSAData;
+use super::util::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+mod default {
+    pub fn values() -> std::collections::HashMap<String, i64> {
+        std::collections::HashMap::new()
+    }
+}
+
+/*
+The parts of an Optolith character export that can be imported
+*/
+#[derive(Deserialize)]
+struct OptolithCharacter {
+    name: String,
+    attr: OptolithAttributes,
+    #[serde(default = "default::values")]
+    talents: HashMap<String, i64>,
+    #[serde(default = "default::values")]
+    ct: HashMap<String, i64>,
+    #[serde(default = "default::values")]
+    spells: HashMap<String, i64>,
+    #[serde(default = "default::values")]
+    liturgies: HashMap<String, i64>,
+}
+
+#[derive(Deserialize)]
+struct OptolithAttributes {
+    values: Vec<OptolithAttribute>,
+}
+
+#[derive(Deserialize)]
+struct OptolithAttribute {
+    id: String,
+    value: i64,
+}
+
+/*
+Converts the given raw character data to the format of The Dark Aid, if it is an Optolith export.
+Returns None for any other data, otherwise the converted character data and a list of
+all Optolith entries that could not be mapped
+*/
+pub fn import_character(
+    raw: &[u8],
+    dsa_data: &DSAData,
+) -> Result<Option<(Vec<u8>, Vec<String>)>, Error> {
+    let value: Value = match serde_json::from_slice(raw) {
+        Ok(v) => v,
+        // Invalid JSON is reported when parsing the character
+        Err(_) => {
+            return Ok(None);
+        }
+    };
+    if !is_optolith_export(&value) {
+        return Ok(None);
+    }
+    let optolith_character: OptolithCharacter = serde_json::from_value(value)?;
+    let (character, unmapped) = convert(optolith_character, dsa_data);
+    Ok(Some((serde_json::to_vec(&character)?, unmapped)))
+}
+
+pub fn output_import_report(unmapped: &[String], output: &mut impl OutputWrapper) {
+    output.output_line(&"Imported character from Optolith");
+    if !unmapped.is_empty() {
+        output.output_line(&format!(
+            "{} entries could not be mapped and were skipped:",
+            unmapped.len()
+        ));
+        for entry in unmapped {
+            output.output_line(entry);
+        }
+    }
+}
+
+// Optolith exports contain the version of the client that created them
+fn is_optolith_export(value: &Value) -> bool {
+    value.get("clientVersion").is_some() && value.get("attr").is_some()
+}
+
+fn convert(character: OptolithCharacter, dsa_data: &DSAData) -> (Value, Vec<String>) {
+    let mut unmapped: Vec<String> = Vec::new();
+    let attributes = map_entries(
+        character.attr.values.iter().map(|a| (&a.id, a.value)),
+        "attribute",
+        dsa_data,
+        &mut unmapped,
+    );
+    let skills = map_entries(
+        character.talents.iter().map(|(id, &level)| (id, level)),
+        "talent",
+        dsa_data,
+        &mut unmapped,
+    );
+    let combat_techniques = map_entries(
+        character.ct.iter().map(|(id, &level)| (id, level)),
+        "combat technique",
+        dsa_data,
+        &mut unmapped,
+    );
+    let spells = map_entries(
+        character.spells.iter().map(|(id, &level)| (id, level)),
+        "spell",
+        dsa_data,
+        &mut unmapped,
+    );
+    let chants = map_entries(
+        character.liturgies.iter().map(|(id, &level)| (id, level)),
+        "liturgy",
+        dsa_data,
+        &mut unmapped,
+    );
+    unmapped.sort();
+    (
+        json!({
+            "name": character.name,
+            "attributes": attributes,
+            "skills": skills,
+            "combattechniques": combat_techniques,
+            "spells": spells,
+            "chants": chants,
+        }),
+        unmapped,
+    )
+}
+
+fn map_entries<'a>(
+    entries: impl Iterator<Item = (&'a String, i64)>,
+    kind: &str,
+    dsa_data: &DSAData,
+    unmapped: &mut Vec<String>,
+) -> Vec<Value> {
+    let mut mapped: Vec<Value> = Vec::new();
+    for (optolith_id, level) in entries {
+        match dsa_data.optolith_ids.get(optolith_id) {
+            Some(id) => {
+                mapped.push(json!({ "id": id, "level": level }));
+            }
+            None => {
+                unmapped.push(format!("Unmapped {} \"{}\"", kind, optolith_id));
+            }
+        }
+    }
+    mapped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::Character;
+    use crate::config::AbstractConfig;
+
+    const EXPORT: &'static str = r#"{
+        "clientVersion": "1.5.2",
+        "name": "Alrik",
+        "attr": {"values": [
+            {"id": "ATTR_1", "value": 14}, {"id": "ATTR_2", "value": 12},
+            {"id": "ATTR_3", "value": 13}, {"id": "ATTR_4", "value": 11},
+            {"id": "ATTR_5", "value": 12}, {"id": "ATTR_6", "value": 13},
+            {"id": "ATTR_7", "value": 12}, {"id": "ATTR_8", "value": 11}
+        ]},
+        "spells": {"SPELL_5": 4},
+        "liturgies": {"LITURGY_5": 3}
+    }"#;
+
+    fn dsa_data() -> DSAData {
+        serde_json::from_str(DSAData::DEFAULT_CONFIG).unwrap()
+    }
+
+    #[test]
+    fn reports_unmapped_spells_and_chants() {
+        let dsa_data = dsa_data();
+        let (raw, unmapped) = import_character(EXPORT.as_bytes(), &dsa_data)
+            .unwrap()
+            .unwrap();
+        // Spells and liturgies without a confirmed id are skipped instead of being imported as another entry
+        assert_eq!(
+            unmapped,
+            vec![
+                String::from("Unmapped liturgy \"LITURGY_5\""),
+                String::from("Unmapped spell \"SPELL_5\"")
+            ]
+        );
+        let character = Character::from_raw(raw).unwrap();
+        assert!(character.validate(&dsa_data).errors.is_empty());
+        assert_eq!(character.get_spells().count(), 0);
+        assert_eq!(character.get_chants().count(), 0);
+    }
+
+    #[test]
+    fn optolith_ids_are_known() {
+        let dsa_data = dsa_data();
+        for (optolith_id, id) in &dsa_data.optolith_ids {
+            let known = match optolith_id.split('_').next().unwrap() {
+                "ATTR" => dsa_data.attributes.contains_key(id),
+                "TAL" => dsa_data.talents.contains_key(id),
+                "CT" => dsa_data.combat_techniques.contains_key(id),
+                "SPELL" => dsa_data.spells.contains_key(id),
+                "LITURGY" => dsa_data.chants.contains_key(id),
+                _ => false,
+            };
+            assert!(known, "{} maps to the unknown id {}", optolith_id, id);
+        }
+    }
+}
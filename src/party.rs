@@ -0,0 +1,68 @@
+use super::character_manager::CharacterId;
+use serde::{Deserialize, Serialize};
+
+mod default {
+    pub fn creator() -> Option<u64> {
+        None
+    }
+}
+
+/*
+A group of characters that play together in a channel, or in a whole guild.
+Commands for the whole group (e.g. initiative) use the party of the channel they were sent in
+*/
+#[derive(Serialize, Deserialize)]
+pub struct Party {
+    pub name: String,
+    pub guild_id: u64,
+    // The channel the party plays in, parties without a channel are used in all channels of the guild
+    pub channel_id: Option<u64>,
+    pub members: Vec<PartyMember>,
+    // The user that created the party, they can disband it like a game master
+    #[serde(default = "default::creator")]
+    pub creator: Option<u64>,
+}
+
+/*
+The user or role acting as game master of a guild. The guild owner always has the rights of a game master
+*/
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum GameMaster {
+    User(u64),
+    Role(u64),
+}
+
+impl GameMaster {
+    pub fn describe(&self) -> String {
+        match self {
+            GameMaster::User(id) => format!("the user with the id {}", id),
+            GameMaster::Role(id) => format!("the role with the id {}", id),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PartyMember {
+    pub user_id: u64,
+    pub character_id: CharacterId,
+}
+
+impl Party {
+    pub fn new(name: String, guild_id: u64, channel_id: Option<u64>, creator: Option<u64>) -> Self {
+        Party {
+            name,
+            guild_id,
+            channel_id,
+            members: Vec::new(),
+            creator,
+        }
+    }
+
+    // Describes where the party plays, using a discord channel mention
+    pub fn location(&self) -> String {
+        match self.channel_id {
+            Some(channel_id) => format!("<#{}>", channel_id),
+            None => String::from("whole server"),
+        }
+    }
+}
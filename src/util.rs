@@ -1,4 +1,8 @@
+use std::ffi::OsString;
 use std::fmt::{self, Display};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 pub struct Error {
     message: String,
@@ -29,6 +33,8 @@ pub enum InputErrorType {
     MissingCharacter,
     CharacterNameTooLong,
     TooManyCharacters,
+    InsufficientAP,
+    MissingPermission,
 }
 
 impl Error {
@@ -75,6 +81,15 @@ impl From<serenity::Error> for Error {
     }
 }
 
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error {
+            message: e.to_string(),
+            err_type: ErrorType::IO(IOErrorType::Unknown),
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.message())
@@ -100,6 +115,157 @@ pub fn uppercase_first(s: &str) -> String {
     }
 }
 
+// Formats seconds since the unix epoch as a UTC date and time, e.g. "2021-05-01 18:30 UTC"
+pub fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let (hour, minute) = ((secs % 86400) / 3600, (secs % 3600) / 60);
+    // Converts the days since the epoch to a date in the proleptic gregorian calendar
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02} UTC",
+        year, month, day, hour, minute
+    )
+}
+
+/*
+Writes a file without ever leaving it in a partially written state: The data is written to a temporary file,
+which is synced to disk and then renamed to the target path.
+Afterwards, the same data is written to a backup file in the same way, so there is always a complete copy
+*/
+pub fn write_file_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+    replace_file(path, data)?;
+    replace_file(&sibling_path(path, "", ".bak"), data)
+}
+
+pub async fn write_file_atomic_async(path: PathBuf, data: Vec<u8>) -> Result<(), Error> {
+    match tokio::task::spawn_blocking(move || write_file_atomic(&path, &data)).await {
+        Ok(result) => result,
+        Err(e) => Err(Error::new(
+            e.to_string(),
+            ErrorType::IO(IOErrorType::Unknown),
+        )),
+    }
+}
+
+/*
+Reads a file written by 'write_file_atomic'. If its content is not valid, the last backup is restored, if it is valid.
+Returns None if the file does not exist: Since files are only ever replaced by a rename, a missing file was removed on purpose
+*/
+pub fn read_file_recovering(
+    path: &Path,
+    is_valid: impl Fn(&[u8]) -> bool,
+) -> Result<Option<Vec<u8>>, Error> {
+    let data = match fs::read(path) {
+        Ok(data) if is_valid(&data) => {
+            return Ok(Some(data));
+        }
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(None);
+        }
+        Err(e) => {
+            return Err(e.into());
+        }
+    };
+    let backup_path = sibling_path(path, "", ".bak");
+    if let Ok(backup) = fs::read(&backup_path) {
+        if is_valid(&backup) {
+            println!(
+                "Found corrupt file \"{}\", restoring the last backup",
+                path.display()
+            );
+            replace_file(path, &backup)?;
+            return Ok(Some(backup));
+        }
+    }
+    // Without a valid backup, the caller has to handle the invalid data
+    Ok(Some(data))
+}
+
+pub async fn read_file_recovering_async(
+    path: PathBuf,
+    is_valid: impl Fn(&[u8]) -> bool + Send + 'static,
+) -> Result<Option<Vec<u8>>, Error> {
+    match tokio::task::spawn_blocking(move || read_file_recovering(&path, is_valid)).await {
+        Ok(result) => result,
+        Err(e) => Err(Error::new(
+            e.to_string(),
+            ErrorType::IO(IOErrorType::Unknown),
+        )),
+    }
+}
+
+// Removes a file written by 'write_file_atomic' together with its backup
+pub fn remove_file_atomic(path: &Path) -> Result<(), Error> {
+    fs::remove_file(path)?;
+    match fs::remove_file(sibling_path(path, "", ".bak")) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+pub async fn remove_file_atomic_async(path: PathBuf) -> Result<(), Error> {
+    match tokio::task::spawn_blocking(move || remove_file_atomic(&path)).await {
+        Ok(result) => result,
+        Err(e) => Err(Error::new(
+            e.to_string(),
+            ErrorType::IO(IOErrorType::Unknown),
+        )),
+    }
+}
+
+/*
+Replaces a file through a synced temporary file like 'write_file_atomic', but without keeping a backup.
+Used for files that belong to the user, e.g. a loaded character
+*/
+pub fn replace_file(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let tmp_path = sibling_path(path, ".", ".tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    // The rename itself is only durable once the directory is synced, which is not possible on every platform
+    if let Some(dir) = path.parent() {
+        if let Ok(dir) = fs::File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+pub async fn replace_file_async(path: PathBuf, data: Vec<u8>) -> Result<(), Error> {
+    match tokio::task::spawn_blocking(move || replace_file(&path, &data)).await {
+        Ok(result) => result,
+        Err(e) => Err(Error::new(
+            e.to_string(),
+            ErrorType::IO(IOErrorType::Unknown),
+        )),
+    }
+}
+
+// Returns a path in the same directory with a prefix and suffix added to the file name
+fn sibling_path(path: &Path, prefix: &str, suffix: &str) -> PathBuf {
+    let mut file_name = OsString::from(prefix);
+    file_name.push(path.file_name().unwrap_or_default());
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
+
+// Replaces all characters that might not be allowed in a file name
+pub fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
 pub trait OutputWrapper {
     fn output(&mut self, msg: &impl fmt::Display);
     fn output_line(&mut self, msg: &impl fmt::Display);
@@ -107,6 +273,9 @@ pub trait OutputWrapper {
 
     //Prints  a formatted table given a vector of its rows (note that any headers must simply be passed as rows/columns)
     fn output_table(&mut self, table: &Vec<Vec<String>>);
+
+    //Attaches a file to the output
+    fn output_file(&mut self, file_name: &str, data: Vec<u8>);
 }
 
 pub struct CLIOutputWrapper;
@@ -129,4 +298,12 @@ impl OutputWrapper for CLIOutputWrapper {
             println!();
         }
     }
+
+    //The CLI saves attached files to the current working directory
+    fn output_file(&mut self, file_name: &str, data: Vec<u8>) {
+        match std::fs::write(file_name, data) {
+            Ok(()) => println!("Saved {}", file_name),
+            Err(e) => println!("Error saving {}: {}", file_name, e),
+        }
+    }
 }